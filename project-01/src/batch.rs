@@ -0,0 +1,40 @@
+/// Puts and deletes applied together by `KvsEngine::apply_batch`
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+impl WriteBatch {
+    /// Return empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set value with key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+        self
+    }
+
+    /// Remove key-value. Keys that don't exist are ignored.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.into()));
+        self
+    }
+
+    /// Number of puts and deletes
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has nothing to apply
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
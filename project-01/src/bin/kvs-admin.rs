@@ -0,0 +1,395 @@
+use clap::{
+    arg_enum, crate_authors, crate_version, value_t_or_exit, App, AppSettings, Arg, ArgMatches,
+    SubCommand,
+};
+use kvs::{
+    EncryptionKey, EngineMarker, KvStore, KvStoreConfig, KvsEngine, Result, SledKvsEngine,
+    WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(PartialEq, Debug)]
+    pub enum KvsEngineType {
+        kvs,
+        sled,
+    }
+}
+
+/// Pairs read from or written to an engine at a time
+const PAGE_SIZE: usize = 1000;
+/// Progress is reported every this many pairs
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// One line of a dump, with the key and value in base64
+#[derive(Serialize, Deserialize)]
+struct DumpRecord {
+    key: String,
+    value: String,
+    /// Expiry of the key, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+fn read_key_file(path: &str) -> Result<EncryptionKey> {
+    EncryptionKey::from_hex(&std::fs::read_to_string(path)?)
+}
+
+/// The key given for the kvs engine, if any
+fn encryption_key(m: &ArgMatches, engine_type: &KvsEngineType) -> Result<Option<EncryptionKey>> {
+    let key = m.value_of("key-file").map(read_key_file).transpose()?;
+    if key.is_some() && *engine_type != KvsEngineType::kvs {
+        clap::Error::with_description(
+            "encryption is only supported by the kvs engine",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    Ok(key)
+}
+
+fn main() -> Result<()> {
+    let engine = Arg::with_name("engine")
+        .long("engine")
+        .possible_values(&KvsEngineType::variants())
+        .case_insensitive(true)
+        .value_name("ENGINE-NAME")
+        .required(false)
+        .takes_value(true);
+    let key_file = Arg::with_name("key-file")
+        .long("key-file")
+        .help("file holding the hex key that encrypts the kvs engine's log")
+        .value_name("PATH")
+        .required(false)
+        .takes_value(true);
+    let dir = Arg::with_name("dir")
+        .value_name("DIR")
+        .required(true)
+        .takes_value(true);
+    let dump = SubCommand::with_name("dump")
+        .about("write every key-value pair of an offline data directory as JSON lines")
+        .arg(
+            engine
+                .clone()
+                .help("engine of the directory, if it can't tell by itself"),
+        )
+        .arg(key_file.clone())
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("file to write instead of stdout")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(dir.clone());
+    let restore = SubCommand::with_name("restore")
+        .about("load a dump into a new data directory, which only appears once all of it is loaded")
+        .arg(engine.default_value("kvs"))
+        .arg(key_file.clone())
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .help("file to read instead of stdin")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(dir.clone());
+    let verify = SubCommand::with_name("verify")
+        .about("check every record of an offline kvs data directory and report damage by offset")
+        .arg(key_file.clone())
+        .arg(dir.clone());
+    let repair = SubCommand::with_name("repair")
+        .about("salvage the readable records of an offline kvs data directory into a new one")
+        .arg(key_file.clone())
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .help("file to write the report of what was lost to, instead of stdout")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(dir.clone())
+        .arg(
+            Arg::with_name("dest")
+                .value_name("DEST")
+                .required(true)
+                .takes_value(true),
+        );
+    let matches = App::new("kvs-admin")
+        .about("maintain kvs data directories")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands(vec![dump, restore, verify, repair])
+        .get_matches();
+
+    match matches.subcommand() {
+        ("dump", Some(d)) => run_dump(d),
+        ("restore", Some(r)) => run_restore(r),
+        ("verify", Some(v)) => run_verify(v),
+        ("repair", Some(r)) => run_repair(r),
+        _ => unreachable!(),
+    }
+}
+
+fn run_dump(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of("dir").unwrap());
+    let engine_type = if m.is_present("engine") {
+        value_t_or_exit!(m, "engine", KvsEngineType)
+    } else {
+        match EngineMarker::read(dir)?.map(|marker| marker.engine.parse::<KvsEngineType>()) {
+            Some(Ok(engine_type)) => engine_type,
+            Some(Err(e)) => clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit(),
+            None => clap::Error::with_description(
+                &format!("no data directory found at {}", dir.display()),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit(),
+        }
+    };
+    EngineMarker::check(dir, &engine_type.to_string())?;
+    let encryption_key = encryption_key(m, &engine_type)?;
+
+    let out: Box<dyn Write> = match m.value_of("output") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    let (dumped, keys) = match engine_type {
+        KvsEngineType::sled => dump_pairs(&SledKvsEngine::open_read_only(dir)?, &mut out)?,
+        KvsEngineType::kvs => {
+            let config = KvStoreConfig {
+                read_only: true,
+                encryption_key,
+                ..KvStoreConfig::default()
+            };
+            dump_pairs(&KvStore::open_with_config(dir, config)?, &mut out)?
+        }
+    };
+    out.flush()?;
+    eprintln!("dumped {} pairs", dumped);
+    // keys expiring meanwhile can make these differ
+    if dumped != keys {
+        eprintln!("the directory holds {} keys", keys);
+    }
+    Ok(())
+}
+
+/// Write every pair of `engine` to `out`.
+/// Returns how many were written and how many keys the engine counted first.
+fn dump_pairs(engine: &impl KvsEngine, out: &mut impl Write) -> Result<(u64, u64)> {
+    let keys = engine.stats()?.keys;
+    let mut dumped = 0;
+    let mut start = Bound::Unbounded;
+    loop {
+        let pairs = engine.scan_bytes((start, Bound::Unbounded), PAGE_SIZE)?;
+        let full = pairs.len() == PAGE_SIZE;
+        for (key, value) in &pairs {
+            let expires_at = match engine.expires_at(key.clone()) {
+                Ok(expires_at) => expires_at.map(millis_since_epoch),
+                // expired since the scan
+                Err(e) if e.is_key_not_found() => continue,
+                Err(e) => return Err(e),
+            };
+            let record = DumpRecord {
+                key: base64::encode(key),
+                value: base64::encode(value),
+                expires_at,
+            };
+            serde_json::to_writer(&mut *out, &record)?;
+            out.write_all(b"\n")?;
+            dumped += 1;
+            if dumped % PROGRESS_INTERVAL == 0 {
+                eprintln!("dumped {} pairs", dumped);
+            }
+        }
+        match pairs.into_iter().last() {
+            Some((key, _)) if full => start = Bound::Excluded(key),
+            _ => return Ok((dumped, keys)),
+        }
+    }
+}
+
+fn run_restore(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of("dir").unwrap());
+    let engine_type = value_t_or_exit!(m, "engine", KvsEngineType);
+    let encryption_key = encryption_key(m, &engine_type)?;
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        clap::Error::with_description(
+            &format!("{} is not empty", dir.display()),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    // load into a directory beside the target, which a failure removes again
+    let parent = match dir.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent)?;
+    let temp = tempfile::Builder::new()
+        .prefix(".kvs-restore")
+        .tempdir_in(parent)?;
+    EngineMarker::claim(temp.path(), &engine_type.to_string())?
+        .set_encrypted(temp.path(), encryption_key.is_some())?;
+
+    let input: Box<dyn Read> = match m.value_of("input") {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(std::io::stdin()),
+    };
+    let input = BufReader::new(input);
+    let restored = match engine_type {
+        KvsEngineType::sled => restore_pairs(&SledKvsEngine::open(temp.path())?, input)?,
+        KvsEngineType::kvs => {
+            let config = KvStoreConfig {
+                encryption_key,
+                ..KvStoreConfig::default()
+            };
+            restore_pairs(&KvStore::open_with_config(temp.path(), config)?, input)?
+        }
+    };
+    eprintln!("restored {} pairs", restored.pairs);
+    // keys expiring meanwhile may be counted or not
+    if restored.keys < restored.live_after || restored.keys > restored.live_before {
+        eprintln!(
+            "verification failed: the directory holds {} keys, expected {}",
+            restored.keys, restored.live_before
+        );
+        temp.close()?;
+        exit(1);
+    }
+    if dir.exists() {
+        std::fs::remove_dir(dir)?;
+    }
+    // dropping `temp` afterwards finds nothing left to remove
+    std::fs::rename(temp.path(), dir)?;
+    Ok(())
+}
+
+/// What `restore_pairs` loaded
+struct Restored {
+    /// Lines read
+    pairs: u64,
+    /// Keys the engine counts afterwards
+    keys: u64,
+    /// Distinct keys read that hadn't expired right before the count
+    live_before: u64,
+    /// Distinct keys read that hadn't expired right after the count
+    live_after: u64,
+}
+
+/// Set every pair read from `input` in `engine`, in order, so the last
+/// line of a key wins. Pairs that have expired already remove the key.
+fn restore_pairs(engine: &impl KvsEngine, input: impl BufRead) -> Result<Restored> {
+    let mut pairs = 0;
+    // expiry of the last pair of each key read
+    let mut expiries = HashMap::new();
+    let mut batch = WriteBatch::new();
+    for (n, line) in input.lines().enumerate() {
+        let record: DumpRecord = serde_json::from_str(&line?)?;
+        let (key, value) = match (base64::decode(&record.key), base64::decode(&record.value)) {
+            (Ok(key), Ok(value)) => (key, value),
+            _ => {
+                let message = format!("line {}: invalid base64", n + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+            }
+        };
+        expiries.insert(key.clone(), record.expires_at);
+        match record.expires_at {
+            None => {
+                batch.set(key, value);
+            }
+            Some(expires_at) => match expires_at.checked_sub(now_millis()) {
+                Some(ttl) if ttl > 0 => {
+                    // the batch holds earlier lines, which go first
+                    engine.apply_batch(std::mem::take(&mut batch))?;
+                    engine.set_with_ttl(key, value, Duration::from_millis(ttl))?;
+                }
+                _ => {
+                    batch.remove(key);
+                }
+            },
+        }
+        if batch.len() == PAGE_SIZE {
+            engine.apply_batch(std::mem::take(&mut batch))?;
+        }
+        pairs += 1;
+        if pairs % PROGRESS_INTERVAL == 0 {
+            eprintln!("restored {} pairs", pairs);
+        }
+    }
+    engine.apply_batch(batch)?;
+
+    let live = |now: u64| {
+        expiries
+            .values()
+            .filter(|x| x.is_none_or(|x| x > now))
+            .count() as u64
+    };
+    let live_before = live(now_millis());
+    let keys = engine.stats()?.keys;
+    Ok(Restored {
+        pairs,
+        keys,
+        live_before,
+        live_after: live(now_millis()),
+    })
+}
+
+/// Milliseconds from the Unix epoch to `time`
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Milliseconds from the Unix epoch to now
+fn now_millis() -> u64 {
+    millis_since_epoch(SystemTime::now())
+}
+
+/// Settings to read a kvs data directory with
+fn kvs_config(m: &ArgMatches) -> Result<KvStoreConfig> {
+    Ok(KvStoreConfig {
+        encryption_key: encryption_key(m, &KvsEngineType::kvs)?,
+        ..KvStoreConfig::default()
+    })
+}
+
+fn run_verify(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of("dir").unwrap());
+    EngineMarker::check(dir, "kvs")?;
+    let report = KvStore::verify(dir, kvs_config(m)?)?;
+    print!("{}", report);
+    if !report.is_clean() {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn run_repair(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of("dir").unwrap());
+    EngineMarker::check(dir, "kvs")?;
+    let report = KvStore::repair(dir, m.value_of("dest").unwrap(), kvs_config(m)?)?;
+    match m.value_of("report") {
+        Some(path) => std::fs::write(path, report.to_string())?,
+        None => print!("{}", report),
+    }
+    eprintln!(
+        "salvaged {} records, lost {} bytes",
+        report.records,
+        report.damaged_bytes()
+    );
+    Ok(())
+}
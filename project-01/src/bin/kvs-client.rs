@@ -1,5 +1,6 @@
 use clap::{crate_authors, crate_version, value_t_or_exit, App, Arg, SubCommand};
 use kvs::{KvsClient, Result};
+use std::io::Write;
 use std::net::SocketAddr;
 use std::process::exit;
 
@@ -58,6 +59,56 @@ fn main() -> Result<()> {
                 .required(true)
                 .takes_value(true),
         );
+    let scan = SubCommand::with_name("scan")
+        .about("list key-value pairs in key order")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .default_value("127.0.0.1:4000")
+                .value_name("IP-PORT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("page-size")
+                .long("page-size")
+                .default_value("100")
+                .value_name("COUNT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("prefix")
+                .value_name("PREFIX")
+                .required(false)
+                .takes_value(true),
+        );
+    let info = SubCommand::with_name("info")
+        .about("show statistics of the server's engine")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .default_value("127.0.0.1:4000")
+                .value_name("IP-PORT")
+                .required(false)
+                .takes_value(true),
+        );
+    let backup = SubCommand::with_name("backup")
+        .about("copy the server's data into a new directory inside the server's backup directory")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .default_value("127.0.0.1:4000")
+                .value_name("IP-PORT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dest")
+                .value_name("DIR")
+                .required(true)
+                .takes_value(true),
+        );
     let matches = App::new("kvs-client")
         .about("communicate kvs-server")
         // use crate_version! to pull the version number
@@ -71,7 +122,7 @@ fn main() -> Result<()> {
                 .required(false)
                 .takes_value(true),
         )
-        .subcommands(vec![set, get, rm])
+        .subcommands(vec![set, get, rm, scan, info, backup])
         .get_matches();
 
     match matches.subcommand() {
@@ -85,10 +136,11 @@ fn main() -> Result<()> {
         }
         ("get", Some(g)) => {
             let addr = value_t_or_exit!(g, "addr", SocketAddr);
-            if let Some(v) =
-                KvsClient::connect(addr)?.get(g.value_of("key").unwrap().to_string())?
-            {
-                println!("{}", v);
+            if let Some(v) = KvsClient::connect(addr)?.get_bytes(g.value_of("key").unwrap())? {
+                // values may not be UTF-8, so write them as they are
+                let mut out = std::io::stdout();
+                out.write_all(&v)?;
+                out.write_all(b"\n")?;
             } else {
                 println!("Key not found");
             }
@@ -106,6 +158,37 @@ fn main() -> Result<()> {
                     e
                 })
         }
+        ("scan", Some(s)) => {
+            let addr = value_t_or_exit!(s, "addr", SocketAddr);
+            let page_size = value_t_or_exit!(s, "page-size", usize);
+            let prefix = s.value_of("prefix").unwrap_or_default();
+            let mut cursor = None;
+            let mut out = std::io::stdout();
+            loop {
+                // the server answers one request per connection
+                let (pairs, next) =
+                    KvsClient::connect(addr)?.scan_bytes(prefix, cursor, page_size)?;
+                for (key, value) in pairs {
+                    out.write_all(&key)?;
+                    out.write_all(b" ")?;
+                    out.write_all(&value)?;
+                    out.write_all(b"\n")?;
+                }
+                cursor = next;
+                if cursor.is_none() {
+                    return Ok(());
+                }
+            }
+        }
+        ("info", Some(i)) => {
+            let addr = value_t_or_exit!(i, "addr", SocketAddr);
+            print!("{}", KvsClient::connect(addr)?.info()?);
+            Ok(())
+        }
+        ("backup", Some(b)) => {
+            let addr = value_t_or_exit!(b, "addr", SocketAddr);
+            KvsClient::connect(addr)?.backup(b.value_of("dest").unwrap())
+        }
         _ => unreachable!(),
     }
 }
@@ -1,8 +1,12 @@
 use clap::{arg_enum, crate_authors, crate_version, value_t_or_exit, App, Arg};
 use kvs::thread_pool::ThreadPool;
-use kvs::{KvStore, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    Compression, Durability, EncryptionKey, EngineMarker, KvStore, KvStoreConfig, KvsServer,
+    Result, SledKvsEngine,
+};
 use slog::*;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -16,6 +20,29 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(PartialEq, Debug)]
+    pub enum DurabilityType {
+        always,
+        periodic,
+        buffered,
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(PartialEq, Debug)]
+    pub enum CompressionType {
+        none,
+        lz4,
+    }
+}
+
+fn read_key_file(path: &str) -> Result<EncryptionKey> {
+    EncryptionKey::from_hex(&std::fs::read_to_string(path)?)
+}
+
 fn main() -> Result<()> {
     run()
 }
@@ -43,17 +70,157 @@ fn run() -> Result<()> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .help("when kvs engine writes are synced to disk")
+                .possible_values(&DurabilityType::variants())
+                .case_insensitive(true)
+                .default_value("buffered")
+                .value_name("MODE")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync-interval")
+                .long("sync-interval")
+                .help("milliseconds between syncs in periodic mode")
+                .default_value("100")
+                .value_name("MS")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .help("codec for values written by the kvs engine")
+                .possible_values(&CompressionType::variants())
+                .case_insensitive(true)
+                .default_value("none")
+                .value_name("CODEC")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compression-threshold")
+                .long("compression-threshold")
+                .help("values shorter than this are stored uncompressed")
+                .default_value("1024")
+                .value_name("BYTES")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("serve the data directory without writing to it; sled only reads one no other server uses")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("key-file")
+                .long("key-file")
+                .help("file holding the hex key that encrypts the kvs engine's log")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key-env")
+                .long("key-env")
+                .help("environment variable holding the hex key that encrypts the kvs engine's log")
+                .conflicts_with("key-file")
+                .value_name("NAME")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("old-key-file")
+                .long("old-key-file")
+                .help("file holding a previous key; the log is rewritten under the new key")
+                .value_name("PATH")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .help("directory clients may write backups into; without it BACKUP is refused")
+                .conflicts_with("read-only")
+                .value_name("DIR")
+                .required(false)
+                .takes_value(true),
+        )
         .get_matches();
 
     let addr = value_t_or_exit!(matches, "addr", SocketAddr);
     let engine_type = value_t_or_exit!(matches, "engine", KvsEngineType);
+    let sync_interval = Duration::from_millis(value_t_or_exit!(matches, "sync-interval", u64));
+    let durability = match value_t_or_exit!(matches, "durability", DurabilityType) {
+        DurabilityType::always => Durability::Always,
+        DurabilityType::periodic => Durability::Periodic(sync_interval),
+        DurabilityType::buffered => Durability::Buffered,
+    };
+    let compression = match value_t_or_exit!(matches, "compression", CompressionType) {
+        CompressionType::none => Compression::None,
+        CompressionType::lz4 => Compression::Lz4,
+    };
+    let compression_threshold = value_t_or_exit!(matches, "compression-threshold", usize);
+    let read_only = matches.is_present("read-only");
+    let backup_dir = matches.value_of("backup-dir").map(PathBuf::from);
+    let encryption_key = match (matches.value_of("key-file"), matches.value_of("key-env")) {
+        (Some(path), _) => Some(read_key_file(path)?),
+        (None, Some(name)) => match std::env::var(name) {
+            Ok(hex) => Some(EncryptionKey::from_hex(&hex)?),
+            Err(_) => clap::Error::with_description(
+                &format!("environment variable {} is not set", name),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit(),
+        },
+        (None, None) => None,
+    };
+    let previous_keys = matches
+        .values_of("old-key-file")
+        .into_iter()
+        .flatten()
+        .map(read_key_file)
+        .collect::<Result<Vec<_>>>()?;
+    let encrypted = encryption_key.is_some() || !previous_keys.is_empty();
+    if engine_type != KvsEngineType::kvs && encrypted {
+        clap::Error::with_description(
+            "encryption is only supported by the kvs engine",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
 
     let json = slog_json::Json::default(std::io::stderr()).fuse();
     let drain = slog_async::Async::new(json).build().fuse();
 
     let root = slog::Logger::root(drain, o!("version" => crate_version!()));
 
-    info!(root, "config" ; "addr" => addr, "engine" => engine_type.to_string());
+    info!(root, "config" ; "addr" => addr, "engine" => engine_type.to_string(), "durability" => format!("{:?}", durability), "compression" => format!("{:?}", compression), "encrypted" => encryption_key.is_some(), "read_only" => read_only);
+
+    let dir = Path::new("./");
+    let engine_name = engine_type.to_string();
+    let marker = if read_only {
+        EngineMarker::check(dir, &engine_name)
+    } else {
+        EngineMarker::claim(dir, &engine_name).map(Some)
+    };
+    let marker = match marker {
+        Ok(marker) => marker,
+        Err(e) => {
+            error!(root, "{}", e);
+            return Err(e);
+        }
+    };
+
+    if let Some(backup_dir) = &backup_dir {
+        std::fs::create_dir_all(backup_dir)?;
+    }
 
     info!(root, "starting");
 
@@ -68,9 +235,56 @@ fn run() -> Result<()> {
         .expect("Error setting Ctrl-C handler");
     let s = match engine_type {
         KvsEngineType::sled => {
-            KvsServer::new(SledKvsEngine::open("./")?, pool).run(addr, server)?
+            let engine = if read_only {
+                SledKvsEngine::open_read_only(dir)?
+            } else {
+                SledKvsEngine::open(dir)?
+            };
+            let s = KvsServer::new(engine, pool);
+            match backup_dir {
+                Some(dir) => s.with_backup_dir(dir).run(addr, server)?,
+                None => s.run(addr, server)?,
+            }
+        }
+        KvsEngineType::kvs => {
+            let config = KvStoreConfig {
+                logger: root.clone(),
+                durability,
+                compression,
+                compression_threshold,
+                encryption_key,
+                previous_keys,
+                read_only,
+                ..KvStoreConfig::default()
+            };
+            let encrypt = config.encryption_key.is_some();
+            // records written before the key was first given are sealed once
+            let sealed = matches!(&marker, Some(m) if m.encrypted);
+            let migrate = encrypt && !read_only && !sealed;
+            if migrate {
+                let config = KvStoreConfig {
+                    accept_plaintext: true,
+                    ..config.clone()
+                };
+                KvStore::open_with_config(dir, config)?.rekey()?;
+                info!(root, "encrypted existing records");
+            }
+            // a read-only store can still read under the previous keys
+            let rotate = !config.previous_keys.is_empty() && !read_only && !migrate;
+            let mut store = KvStore::open_with_config(dir, config)?;
+            if rotate {
+                store.rekey()?;
+                info!(root, "rotated encryption key");
+            }
+            if let Some(mut marker) = marker.filter(|_| !read_only) {
+                marker.set_encrypted(dir, encrypt)?;
+            }
+            let s = KvsServer::new(store, pool);
+            match backup_dir {
+                Some(dir) => s.with_backup_dir(dir).run(addr, server)?,
+                None => s.run(addr, server)?,
+            }
         }
-        KvsEngineType::kvs => KvsServer::new(KvStore::open("./")?, pool).run(addr, server)?,
     };
     while running.load(Ordering::Relaxed) {}
     info!(root, "stopping server...");
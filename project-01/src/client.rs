@@ -1,9 +1,16 @@
-use crate::command::{Request, Response};
+use crate::command::{parse_stats, Request, Response};
+use crate::engine::{into_string, into_string_pairs, EngineStats, Pair};
 use crate::error::{KvsError, KvsErrorKind};
 use crate::Result;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::str::FromStr;
+use std::time::Duration;
+
+/// Scanned pairs and the cursor of the next page
+type ScanPage = (Vec<(String, String)>, Option<String>);
+/// Scanned pairs and the cursor of the next page, as bytes
+type ByteScanPage = (Vec<Pair>, Option<Vec<u8>>);
 
 /// Key value store client
 pub struct KvsClient {
@@ -23,8 +30,14 @@ impl KvsClient {
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
+    /// Fails with `Encoding` if the value isn't UTF-8.
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(into_string).transpose()
+    }
+
+    /// Get the value of a given key from the server as bytes.
+    pub fn get_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let request = Request::Get { key: key.into() };
         self.communicate(&request).map_or_else(
             |e| {
                 if e.is_key_not_found() {
@@ -34,33 +47,171 @@ impl KvsClient {
                 }
             },
             |x| match x {
-                Response::String { value } => Ok(Some(value)),
-                _ => Err(KvsError::from(KvsErrorKind::UnknownCommand(x.to_string()))),
+                Response::Value { value } => Ok(Some(value)),
+                _ => Err(KvsError::from(KvsErrorKind::UnknownCommand(format!(
+                    "{:?}",
+                    x
+                )))),
             },
         )
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
+    /// Set the value of a key in the server.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+        };
         let _response = self.communicate(&request)?;
         Ok(())
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
+    /// Remove a key in the server.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Remove { key: key.into() };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Set the value of a key in the server unless the key exists.
+    /// Fails with `ConditionFailed` otherwise.
+    pub fn set_if_absent(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Set the value of a key in the server only if the key exists.
+    /// Fails with `ConditionFailed` otherwise.
+    pub fn set_if_present(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::SetIfPresent {
+            key: key.into(),
+            value: value.into(),
+        };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Set the value of a key in the server only if its current value
+    /// is `expected`. Fails with `ConditionFailed` otherwise.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = Request::CompareAndSwap {
+            key: key.into(),
+            expected: expected.into(),
+            value: value.into(),
+        };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Set the value of a key in the server, which expires after `ttl`.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let request = Request::SetWithTtl {
+            key: key.into(),
+            value: value.into(),
+            ttl,
+        };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Make a key in the server expire after `ttl`, or never with `None`.
+    pub fn expire(&mut self, key: impl Into<Vec<u8>>, ttl: Option<Duration>) -> Result<()> {
+        let request = Request::Expire {
+            key: key.into(),
+            ttl,
+        };
+        let _response = self.communicate(&request)?;
+        Ok(())
+    }
+
+    /// Scan the keys starting with `prefix` in the server, in key order.
+    /// Returns at most `limit` pairs after `cursor`, fewer if the server caps
+    /// the page, and the cursor of the next page, which is `None` once the
+    /// scan is complete.
+    /// Fails with `Encoding` if a key or value isn't UTF-8.
+    pub fn scan(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let (pairs, cursor) = self.scan_bytes(prefix, cursor.map(String::into_bytes), limit)?;
+        Ok((
+            into_string_pairs(pairs)?,
+            cursor.map(into_string).transpose()?,
+        ))
+    }
+
+    /// `scan` with keys, values and cursors as bytes
+    pub fn scan_bytes(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<ByteScanPage> {
+        let request = Request::Scan {
+            prefix: prefix.into(),
+            cursor,
+            limit,
+        };
+        match self.communicate(&request)? {
+            Response::Pairs { pairs, cursor } => Ok((pairs, cursor)),
+            x => Err(KvsError::from(KvsErrorKind::UnknownCommand(format!(
+                "{:?}",
+                x
+            )))),
+        }
+    }
+
+    /// Statistics of the server's engine
+    pub fn info(&mut self) -> Result<EngineStats> {
+        match self.communicate(&Request::Info)? {
+            Response::Value { value } => parse_stats(&value),
+            x => Err(KvsError::from(KvsErrorKind::UnknownCommand(format!(
+                "{:?}",
+                x
+            )))),
+        }
+    }
+
+    /// Make the server checkpoint its engine into `dest`, a relative path
+    /// inside the server's backup directory that must not exist yet.
+    /// Fails with `Forbidden` if the server takes no backups or `dest`
+    /// leads outside that directory.
+    pub fn backup(&mut self, dest: impl Into<String>) -> Result<()> {
+        let request = Request::Backup {
+            dest: dest.into().into_bytes(),
+        };
         let _response = self.communicate(&request)?;
         Ok(())
     }
 
     fn communicate(&mut self, request: &Request) -> Result<Response> {
-        let send = request.to_string();
-        self.writer.write_all(send.as_bytes())?;
+        self.writer.write_all(&request.encode())?;
         self.writer.flush()?;
-        let mut receive = String::new();
-        self.reader.read_line(&mut receive)?;
-        Response::from_str(receive.as_str()).and_then(|x| match x {
+        Response::read(&mut self.reader).and_then(|x| match x {
             Response::Error { message } => {
                 let e = KvsErrorKind::from_str(message.as_str())?;
                 Err(KvsError::from(e))
@@ -1,129 +1,546 @@
+use crate::engine::{EngineStats, Pair};
 use crate::error::{KvsError, KvsErrorKind};
 use crate::Result;
-use std::iter::Iterator;
-use std::str::FromStr;
+use std::io::{BufRead, Read};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Longest bulk string accepted from the network
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+/// Longest status, error or length line accepted from the network
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// Most elements accepted in an array from the network
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Expire {
+        key: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    /// Keys starting with `prefix` after `cursor`, in key order
+    Scan {
+        prefix: Vec<u8>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    },
+    /// Engine statistics
+    Info,
+    /// Checkpoint the engine into `dest`, a path inside the server's backup directory
+    Backup {
+        dest: Vec<u8>,
+    },
 }
 
 #[derive(Debug)]
 pub enum Response {
-    String { value: String },
-    Error { message: String },
+    /// Success without a value
+    Ok,
+    Value {
+        value: Vec<u8>,
+    },
+    Error {
+        message: String,
+    },
+    /// A page of scanned pairs, with the cursor of the next page if there may be one
+    Pairs {
+        pairs: Vec<Pair>,
+        cursor: Option<Vec<u8>>,
+    },
+}
+
+/// A RESP-like frame: lines for status and errors, length-prefixed bulk
+/// strings for keys and values, so both may hold any bytes
+#[derive(Debug)]
+enum Frame {
+    /// `+<line>\r\n`
+    Simple(String),
+    /// `-<line>\r\n`
+    Error(String),
+    /// `$<len>\r\n<bytes>\r\n`, or `$-1\r\n` for nil
+    Bulk(Option<Vec<u8>>),
+    /// `*<count>\r\n` followed by the elements
+    Array(Vec<Frame>),
 }
 
-impl ToString for Response {
-    fn to_string(&self) -> String {
+impl Frame {
+    fn write(&self, out: &mut Vec<u8>) {
         match self {
-            Response::String { value } => format!("+{}\r\n", value),
-            Response::Error { message } => format!("-{}\r\n", message),
+            Frame::Simple(line) => out.extend(format!("+{}\r\n", line).bytes()),
+            Frame::Error(line) => out.extend(format!("-{}\r\n", line).bytes()),
+            Frame::Bulk(Some(bytes)) => {
+                out.extend(format!("${}\r\n", bytes.len()).bytes());
+                out.extend(bytes);
+                out.extend(b"\r\n");
+            }
+            Frame::Bulk(None) => out.extend(b"$-1\r\n"),
+            Frame::Array(frames) => {
+                out.extend(format!("*{}\r\n", frames.len()).bytes());
+                for frame in frames {
+                    frame.write(out);
+                }
+            }
         }
     }
-}
 
-impl FromStr for Response {
-    type Err = KvsError;
+    /// Read one frame. Requests and responses are flat, so an array may
+    /// not hold another array.
+    fn read(reader: &mut impl BufRead) -> Result<Self> {
+        let line = read_line(reader)?;
+        let count = match line.strip_prefix('*') {
+            Some(count) => parse_len(count, MAX_ARRAY_LEN)?,
+            None => return Frame::read_element(reader, &line),
+        };
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let line = read_line(reader)?;
+            frames.push(Frame::read_element(reader, &line)?);
+        }
+        Ok(Frame::Array(frames))
+    }
 
-    fn from_str(s: &str) -> Result<Self> {
-        let first = s
-            .chars()
-            .next()
-            .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?;
-        match first {
-            '+' => Ok(Response::String {
-                value: s
-                    .trim_end_matches("\r\n")
-                    .trim_start_matches("+")
-                    .to_string(),
-            }),
-            '-' => Ok(Response::Error {
-                message: s
-                    .trim_end_matches("\r\n")
-                    .trim_start_matches("-")
-                    .to_string(),
-            }),
+    /// Read the rest of the frame that starts with `line`, which is any
+    /// frame but an array
+    fn read_element(reader: &mut impl BufRead, line: &str) -> Result<Self> {
+        if !line.is_char_boundary(1) {
+            return Err(KvsError::from(KvsErrorKind::InvalidArgument));
+        }
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Ok(Frame::Simple(rest.to_string())),
+            "-" => Ok(Frame::Error(rest.to_string())),
+            "$" if rest == "-1" => Ok(Frame::Bulk(None)),
+            "$" => {
+                let len = parse_len(rest, MAX_BULK_LEN)?;
+                // the buffer only grows as the bytes arrive, whatever length is claimed
+                let mut bytes = Vec::new();
+                reader
+                    .by_ref()
+                    .take(len as u64 + 2)
+                    .read_to_end(&mut bytes)?;
+                if bytes.len() != len + 2 || !bytes.ends_with(b"\r\n") {
+                    return Err(KvsError::from(KvsErrorKind::InvalidArgument));
+                }
+                bytes.truncate(len);
+                Ok(Frame::Bulk(Some(bytes)))
+            }
             _ => Err(KvsError::from(KvsErrorKind::InvalidArgument)),
         }
     }
-}
 
-impl ToString for Request {
-    fn to_string(&self) -> String {
+    fn into_bulk(self) -> Result<Option<Vec<u8>>> {
         match self {
-            Request::Get { key } => format!("GET {key}\r\n", key = key),
-            Request::Set { key, value } => {
-                format!("SET {key} {value}\r\n", key = key, value = value)
-            }
-            Request::Remove { key } => format!("REMOVE {key}\r\n", key = key),
+            Frame::Bulk(bytes) => Ok(bytes),
+            _ => Err(KvsError::from(KvsErrorKind::InvalidArgument)),
         }
     }
 }
 
-impl FromStr for Request {
-    type Err = KvsError;
+/// A `\r\n` terminated line, without the terminator
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.len() < 3 || !line.ends_with(b"\r\n") {
+        return Err(KvsError::from(KvsErrorKind::InvalidArgument));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| KvsError::from(KvsErrorKind::InvalidArgument))
+}
+
+fn parse_len(s: &str, max: usize) -> Result<usize> {
+    s.parse::<usize>()
+        .ok()
+        .filter(|&len| len <= max)
+        .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))
+}
+
+fn parse_millis(bytes: &[u8]) -> Result<Duration> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))
+}
+
+fn bulk(bytes: &[u8]) -> Frame {
+    Frame::Bulk(Some(bytes.to_vec()))
+}
+
+fn bulk_cursor(cursor: &Option<Vec<u8>>) -> Frame {
+    Frame::Bulk(cursor.clone())
+}
+
+impl Response {
+    /// Encode for sending
+    pub fn encode(&self) -> Vec<u8> {
+        let frame = match self {
+            Response::Ok => Frame::Simple("OK".to_string()),
+            Response::Value { value } => bulk(value),
+            Response::Error { message } => Frame::Error(message.clone()),
+            // the cursor, then each key followed by its value
+            Response::Pairs { pairs, cursor } => {
+                let mut frames = vec![bulk_cursor(cursor)];
+                for (key, value) in pairs {
+                    frames.push(bulk(key));
+                    frames.push(bulk(value));
+                }
+                Frame::Array(frames)
+            }
+        };
+        let mut out = Vec::new();
+        frame.write(&mut out);
+        out
+    }
 
-    fn from_str(s: &str) -> Result<Self> {
-        let mut xs = s.trim_end_matches("\r\n").splitn(3, " ");
-        let command: &str = xs
+    /// Read one response from `reader`
+    pub fn read(reader: &mut impl BufRead) -> Result<Self> {
+        match Frame::read(reader)? {
+            Frame::Simple(_) => Ok(Response::Ok),
+            Frame::Error(message) => Ok(Response::Error { message }),
+            Frame::Bulk(Some(value)) => Ok(Response::Value { value }),
+            Frame::Array(frames) if frames.len() % 2 == 1 => {
+                let mut frames = frames.into_iter();
+                let cursor = frames.next().map_or(Ok(None), Frame::into_bulk)?;
+                let mut pairs = Vec::new();
+                while let (Some(key), Some(value)) = (frames.next(), frames.next()) {
+                    pairs.push((required(key.into_bulk()?)?, required(value.into_bulk()?)?));
+                }
+                Ok(Response::Pairs { pairs, cursor })
+            }
+            _ => Err(KvsError::from(KvsErrorKind::InvalidArgument)),
+        }
+    }
+}
+
+/// Parse the lines `EngineStats` displays as, ignoring figures it doesn't know
+pub fn parse_stats(info: &[u8]) -> Result<EngineStats> {
+    let invalid = || KvsError::from(KvsErrorKind::InvalidArgument);
+    let info = std::str::from_utf8(info).map_err(|_| invalid())?;
+    let mut stats = EngineStats::default();
+    for line in info.lines() {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let value = parts
             .next()
-            .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?;
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or_else(invalid)?;
+        match name {
+            "keys" => stats.keys = value,
+            "log_bytes" => stats.log_bytes = value,
+            "stale_bytes" => stats.stale_bytes = Some(value),
+            "segments" => stats.segments = Some(value),
+            "last_compaction" => {
+                stats.last_compaction = Some(UNIX_EPOCH + Duration::from_millis(value))
+            }
+            _ => {}
+        }
+    }
+    Ok(stats)
+}
 
-        match command {
-            "GET" => {
-                let key = xs
-                    .next()
-                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?
-                    .to_string();
-                Ok(Request::Get { key })
+fn required(bytes: Option<Vec<u8>>) -> Result<Vec<u8>> {
+    bytes.ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))
+}
+
+impl Request {
+    /// Encode for sending: an array of the command name and its arguments
+    pub fn encode(&self) -> Vec<u8> {
+        let millis = |ttl: &Duration| Frame::Bulk(Some(ttl.as_millis().to_string().into_bytes()));
+        let frames = match self {
+            Request::Get { key } => vec![bulk(b"GET"), bulk(key)],
+            Request::Set { key, value } => vec![bulk(b"SET"), bulk(key), bulk(value)],
+            Request::Remove { key } => vec![bulk(b"REMOVE"), bulk(key)],
+            Request::SetIfAbsent { key, value } => {
+                vec![bulk(b"SETIFABSENT"), bulk(key), bulk(value)]
             }
-            "SET" => {
-                let key = xs
-                    .next()
-                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?
-                    .to_string();
-                let value = xs
-                    .next()
-                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?
-                    .to_string();
-                Ok(Request::Set { key, value })
+            Request::SetIfPresent { key, value } => {
+                vec![bulk(b"SETIFPRESENT"), bulk(key), bulk(value)]
             }
-            "REMOVE" => {
-                let key = xs
+            Request::CompareAndSwap {
+                key,
+                expected,
+                value,
+            } => vec![bulk(b"CAS"), bulk(key), bulk(expected), bulk(value)],
+            Request::SetWithTtl { key, value, ttl } => {
+                vec![bulk(b"SETEX"), bulk(key), millis(ttl), bulk(value)]
+            }
+            Request::Expire {
+                key,
+                ttl: Some(ttl),
+            } => vec![bulk(b"EXPIRE"), bulk(key), millis(ttl)],
+            Request::Expire { key, ttl: None } => vec![bulk(b"PERSIST"), bulk(key)],
+            Request::Scan {
+                prefix,
+                cursor,
+                limit,
+            } => vec![
+                bulk(b"SCAN"),
+                bulk_cursor(cursor),
+                bulk(limit.to_string().as_bytes()),
+                bulk(prefix),
+            ],
+            Request::Info => vec![bulk(b"INFO")],
+            Request::Backup { dest } => vec![bulk(b"BACKUP"), bulk(dest)],
+        };
+        let mut out = Vec::new();
+        Frame::Array(frames).write(&mut out);
+        out
+    }
+
+    /// Read one request from `reader`
+    pub fn read(reader: &mut impl BufRead) -> Result<Self> {
+        let frames = match Frame::read(reader)? {
+            Frame::Array(frames) => frames,
+            _ => return Err(KvsError::from(KvsErrorKind::InvalidArgument)),
+        };
+        // the cursor of SCAN is the only argument that may be nil
+        let mut xs = frames
+            .into_iter()
+            .map(Frame::into_bulk)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter();
+        let command = required(xs.next().flatten())?;
+        let mut arg = || required(xs.next().flatten());
+
+        let request = match command.as_slice() {
+            b"GET" => Request::Get { key: arg()? },
+            b"SET" => Request::Set {
+                key: arg()?,
+                value: arg()?,
+            },
+            b"REMOVE" => Request::Remove { key: arg()? },
+            b"SETIFABSENT" => Request::SetIfAbsent {
+                key: arg()?,
+                value: arg()?,
+            },
+            b"SETIFPRESENT" => Request::SetIfPresent {
+                key: arg()?,
+                value: arg()?,
+            },
+            b"CAS" => Request::CompareAndSwap {
+                key: arg()?,
+                expected: arg()?,
+                value: arg()?,
+            },
+            b"SETEX" => {
+                let key = arg()?;
+                let ttl = parse_millis(&arg()?)?;
+                Request::SetWithTtl {
+                    key,
+                    value: arg()?,
+                    ttl,
+                }
+            }
+            b"EXPIRE" => Request::Expire {
+                key: arg()?,
+                ttl: Some(parse_millis(&arg()?)?),
+            },
+            b"PERSIST" => Request::Expire {
+                key: arg()?,
+                ttl: None,
+            },
+            b"SCAN" => {
+                let cursor = xs
                     .next()
-                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?
-                    .to_string();
-                Ok(Request::Remove { key })
+                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?;
+                let mut arg = || required(xs.next().flatten());
+                let limit = std::str::from_utf8(&arg()?)
+                    .ok()
+                    .and_then(|limit| limit.parse::<usize>().ok())
+                    .filter(|&limit| limit > 0)
+                    .ok_or_else(|| KvsError::from(KvsErrorKind::InvalidArgument))?;
+                Request::Scan {
+                    prefix: arg()?,
+                    cursor,
+                    limit,
+                }
             }
-            _ => Err(KvsError::from(KvsErrorKind::InvalidArgument)),
+            b"INFO" => Request::Info,
+            b"BACKUP" => Request::Backup { dest: arg()? },
+            _ => return Err(KvsError::from(KvsErrorKind::InvalidArgument)),
+        };
+        if xs.next().is_some() {
+            return Err(KvsError::from(KvsErrorKind::InvalidArgument));
         }
+        Ok(request)
     }
 }
 
 mod tests {
 
+    #[cfg(test)]
+    fn round_trip(input: &[u8]) -> crate::command::Request {
+        use crate::command::Request;
+
+        let to = Request::read(&mut &input[..]).unwrap();
+        let from = to.encode();
+        assert_eq!(input, from.as_slice());
+        to
+    }
+
     #[test]
     fn get_request_from_to() {
+        round_trip(b"*2\r\n$3\r\nGET\r\n$4\r\nTEST\r\n");
+    }
+
+    #[test]
+    fn set_request_from_to() {
+        round_trip(b"*3\r\n$3\r\nSET\r\n$4\r\nTEST\r\n$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn binary_request_from_to() {
         use crate::command::Request;
-        use std::str::FromStr;
 
-        let input = "GET TEST\r\n";
-        let to = Request::from_str(input).unwrap();
-        let from = to.to_string();
-        assert_eq!(input, from.as_str());
+        let input = b"*3\r\n$3\r\nSET\r\n$5\r\na key\r\n$4\r\n\xff\r\n\x00\r\n";
+        match round_trip(input) {
+            Request::Set { key, value } => {
+                assert_eq!(b"a key", key.as_slice());
+                assert_eq!(b"\xff\r\n\x00", value.as_slice());
+            }
+            x => panic!("unexpected request {:?}", x),
+        }
+        // a bulk string shorter than its length
+        assert!(Request::read(&mut &b"*2\r\n$3\r\nGET\r\n$9\r\nTEST\r\n"[..]).is_err());
+        assert!(Request::read(&mut &b"GET TEST\r\n"[..]).is_err());
     }
 
     #[test]
-    fn set_request_from_to() {
+    fn nested_and_oversized_requests() {
+        use crate::command::Request;
+
+        assert!(Request::read(&mut &b"*1\r\n*1\r\n$3\r\nGET\r\n"[..]).is_err());
+        // would overflow the stack if every level were read recursively
+        let deep = b"*1\r\n".repeat(2_000_000);
+        assert!(Request::read(&mut &deep[..]).is_err());
+        // a length beyond the limit, and one far beyond the bytes that follow
+        let input = b"*2\r\n$3\r\nGET\r\n$536870912\r\nab\r\n";
+        assert!(Request::read(&mut &input[..]).is_err());
+        let input = b"*2\r\n$3\r\nGET\r\n$60000000\r\nab\r\n";
+        assert!(Request::read(&mut &input[..]).is_err());
+        let long_line = [&b"*2\r\n$"[..], &b"1".repeat(100_000)].concat();
+        assert!(Request::read(&mut &long_line[..]).is_err());
+    }
+
+    #[test]
+    fn conditional_set_request_from_to() {
+        round_trip(b"*3\r\n$11\r\nSETIFABSENT\r\n$4\r\nTEST\r\n$1\r\n1\r\n");
+        round_trip(b"*3\r\n$12\r\nSETIFPRESENT\r\n$4\r\nTEST\r\n$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn cas_request_from_to() {
         use crate::command::Request;
-        use std::str::FromStr;
 
-        let input = "SET TEST 1\r\n";
-        let to = Request::from_str(input).unwrap();
-        let from = to.to_string();
-        assert_eq!(input, from.as_str());
+        let input = b"*4\r\n$3\r\nCAS\r\n$4\r\nTEST\r\n$3\r\na b\r\n$9\r\nnew value\r\n";
+        match round_trip(input) {
+            Request::CompareAndSwap {
+                expected, value, ..
+            } => {
+                assert_eq!(b"a b", expected.as_slice());
+                assert_eq!(b"new value", value.as_slice());
+            }
+            x => panic!("unexpected request {:?}", x),
+        }
+        assert!(Request::read(&mut &b"*3\r\n$3\r\nCAS\r\n$4\r\nTEST\r\n$1\r\na\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn expiry_request_from_to() {
+        use crate::command::Request;
+
+        round_trip(b"*4\r\n$5\r\nSETEX\r\n$4\r\nTEST\r\n$4\r\n1500\r\n$3\r\na b\r\n");
+        round_trip(b"*3\r\n$6\r\nEXPIRE\r\n$4\r\nTEST\r\n$4\r\n1500\r\n");
+        round_trip(b"*2\r\n$7\r\nPERSIST\r\n$4\r\nTEST\r\n");
+        let input = b"*3\r\n$6\r\nEXPIRE\r\n$4\r\nTEST\r\n$4\r\nsoon\r\n";
+        assert!(Request::read(&mut &input[..]).is_err());
+    }
+
+    #[test]
+    fn scan_request_from_to() {
+        use crate::command::Request;
+
+        round_trip(b"*4\r\n$4\r\nSCAN\r\n$-1\r\n$2\r\n10\r\n$0\r\n\r\n");
+        round_trip(b"*4\r\n$4\r\nSCAN\r\n$4\r\nkey1\r\n$2\r\n10\r\n$3\r\nkey\r\n");
+        let input = b"*4\r\n$4\r\nSCAN\r\n$-1\r\n$1\r\n0\r\n$3\r\nkey\r\n";
+        assert!(Request::read(&mut &input[..]).is_err());
+    }
+
+    #[test]
+    fn info_request_from_to() {
+        use crate::command::{parse_stats, Request};
+        use crate::EngineStats;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        round_trip(b"*1\r\n$4\r\nINFO\r\n");
+        let stats = EngineStats {
+            keys: 3,
+            log_bytes: 120,
+            stale_bytes: Some(40),
+            segments: Some(2),
+            last_compaction: Some(UNIX_EPOCH + Duration::from_millis(1500)),
+        };
+        assert_eq!(stats, parse_stats(stats.to_string().as_bytes()).unwrap());
+        let stats = EngineStats {
+            keys: 3,
+            ..EngineStats::default()
+        };
+        assert_eq!(stats, parse_stats(stats.to_string().as_bytes()).unwrap());
+        assert!(parse_stats(b"keys:many\n").is_err());
+        assert!(Request::read(&mut &b"*2\r\n$4\r\nINFO\r\n$1\r\nx\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn backup_request_from_to() {
+        use crate::command::Request;
+
+        round_trip(b"*2\r\n$6\r\nBACKUP\r\n$11\r\n/tmp/backup\r\n");
+        assert!(Request::read(&mut &b"*1\r\n$6\r\nBACKUP\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn pairs_response_from_to() {
+        use crate::command::Response;
+
+        for input in &[
+            &b"*1\r\n$-1\r\n"[..],
+            &b"*5\r\n$1\r\nb\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$3\r\n2 3\r\n"[..],
+        ] {
+            let to = Response::read(&mut &input[..]).unwrap();
+            let from = to.encode();
+            assert_eq!(*input, from.as_slice());
+        }
+        let input = b"*2\r\n$-1\r\n$1\r\na\r\n";
+        assert!(Response::read(&mut &input[..]).is_err());
     }
 }
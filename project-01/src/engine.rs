@@ -1,15 +1,238 @@
-use crate::Result;
+use crate::error::{KvsError, KvsErrorKind};
+use crate::{Result, WriteBatch};
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Key Value store trait
+///
+/// Keys and values are bytes; anything that converts into bytes, like a
+/// `String`, can be passed in. `get`, `scan` and `scan_prefix` return
+/// strings and fail with `Encoding` on other data, the `_bytes` methods
+/// return the bytes as stored.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view returned by `snapshot`
+    type Snapshot: KvsSnapshot;
+
     /// Set value with key
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
     /// Get value by key
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    /// Get value by key as a string
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(into_string).transpose()
+    }
     /// Remove key-value
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+    /// Apply all puts and deletes of `batch`, or none of them
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Set value with key unless the key exists.
+    /// Fails with `ConditionFailed` otherwise.
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    /// Set value with key only if the key exists.
+    /// Fails with `ConditionFailed` otherwise.
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    /// Set value with key only if its current value is `expected`.
+    /// Fails with `ConditionFailed` otherwise.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()>;
+    /// Set value with key, which expires after `ttl`
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Make key expire after `ttl`, or never with `None`.
+    /// Fails with `KeyNotFound` if the key doesn't exist.
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Option<Duration>) -> Result<()>;
+    /// When key expires, or `None` if it never does.
+    /// Fails with `KeyNotFound` if the key doesn't exist.
+    fn expires_at(&self, key: impl Into<Vec<u8>>) -> Result<Option<SystemTime>>;
+    /// Keys in `range` with their values, in key order, at most `limit` of them
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>>;
+    /// Keys starting with `prefix` with their values, in key order, at most
+    /// `limit` of them
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        self.scan_bytes(prefix_range(prefix.into()), limit)
+    }
+    /// `scan_bytes` with string keys and values
+    fn scan(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        // strings order like their UTF-8 bytes
+        let range = (
+            bound_bytes(range.start_bound()),
+            bound_bytes(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+    /// `scan_prefix_bytes` with string keys and values
+    fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix, limit)?)
+    }
+    /// Read-only view of the current contents, which later writes and
+    /// compaction don't change
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Size and usage figures, to tell how big the store is and whether it
+    /// needs compacting
+    fn stats(&self) -> Result<EngineStats>;
+    /// Write a copy of the current contents to `dest`, a directory that
+    /// must not exist yet and then opens like the original.
+    /// The copy shows the contents at one point in time; depending on the
+    /// engine, writes carry on or wait while it is taken.
+    /// Fails with `ReadOnly` on a read-only engine.
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()>;
+}
+
+/// Figures reported by `KvsEngine::stats`.
+/// Those an engine doesn't track are `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EngineStats {
+    /// Keys that haven't expired
+    pub keys: u64,
+    /// Bytes the engine's files take on disk
+    pub log_bytes: u64,
+    /// Bytes of overwritten, removed and expired records that compaction
+    /// would reclaim
+    pub stale_bytes: Option<u64>,
+    /// Log segment files
+    pub segments: Option<u64>,
+    /// When compaction last finished
+    pub last_compaction: Option<SystemTime>,
+}
+
+/// Read-only view of an engine at the moment it was taken.
+/// Keys that expire later stay readable in it.
+pub trait KvsSnapshot: Send + 'static {
+    /// Get value by key
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    /// Get value by key as a string
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(into_string).transpose()
+    }
+    /// Keys in `range` with their values, in key order, at most `limit` of them
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>>;
+    /// Keys starting with `prefix` with their values, in key order, at most
+    /// `limit` of them
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        self.scan_bytes(prefix_range(prefix.into()), limit)
+    }
+    /// `scan_bytes` with string keys and values
+    fn scan(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let range = (
+            bound_bytes(range.start_bound()),
+            bound_bytes(range.end_bound()),
+        );
+        into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+    /// `scan_prefix_bytes` with string keys and values
+    fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix, limit)?)
+    }
+}
+
+/// One `name:value` line per figure, leaving out those that are `None`.
+/// The time of the last compaction is in milliseconds since the Unix epoch.
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "keys:{}", self.keys)?;
+        writeln!(f, "log_bytes:{}", self.log_bytes)?;
+        if let Some(stale_bytes) = self.stale_bytes {
+            writeln!(f, "stale_bytes:{}", stale_bytes)?;
+        }
+        if let Some(segments) = self.segments {
+            writeln!(f, "segments:{}", segments)?;
+        }
+        if let Some(at) = self.last_compaction {
+            let millis = at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            writeln!(f, "last_compaction:{}", millis)?;
+        }
+        Ok(())
+    }
+}
+
+/// Key and value
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+pub(crate) fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| KvsError::from(KvsErrorKind::Encoding))
+}
+
+pub(crate) fn into_string_pairs(pairs: Vec<Pair>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+        .collect()
+}
+
+fn bound_bytes(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(x) => Bound::Included(x.clone().into_bytes()),
+        Bound::Excluded(x) => Bound::Excluded(x.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Range of the keys starting with `prefix`: up to the prefix with its last
+/// byte incremented, after dropping trailing 0xff bytes
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// Whether `range` can't hold any key.
+/// `BTreeMap::range` panics on such ranges.
+pub(crate) fn is_empty_range(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Expiry time, in milliseconds since the Unix epoch, of a key set now with `ttl`
+pub(crate) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Time of an expiry given in milliseconds since the Unix epoch
+pub(crate) fn expiry_time(expires_at: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(expires_at)
 }
-pub use crate::engine::sled::SledKvsEngine;
+pub use crate::engine::marker::EngineMarker;
+pub use crate::engine::sled::{SledKvsEngine, SledSnapshot};
 
 mod kvs;
+mod marker;
 mod sled;
@@ -1,58 +1,153 @@
-use crate::engine::KvsEngine;
+use crate::batch::{BatchOp, WriteBatch};
+use crate::engine::{expiry_after, expiry_time, now_millis, EngineStats, KvsEngine, Pair};
 use crate::error::{KvsError, KvsErrorKind};
-use crate::kv::{Command, KvStore};
+use crate::kv::{Command, Condition, KvStore, KvStoreSnapshot};
 use crate::Result;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let command = Command::Set((key.clone(), value));
-        let s: String = serde_json::to_string(&command)?;
-        let mut w = self.writer.lock().expect("set: cant write");
-        w.write_fmt(format_args!("{}\n", s))?;
-        w.flush()?;
-        let mut pos = self.next_pos.write().unwrap();
-        self.index
-            .write()
-            .expect("set: cant insert")
-            .insert(key, *pos);
-        *pos += 1;
+    type Snapshot = KvStoreSnapshot;
+
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.commit(Command::Set((key.into(), value.into())), None)?;
+        self.maybe_compact();
 
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(&i) = self.index.read().unwrap().get(key.as_str()) {
-            let reader = BufReader::new(File::open(self.path.as_path())?);
-            let s = reader
-                .lines()
-                .nth(i)
-                .ok_or_else(|| KvsError::from(KvsErrorKind::Index))?;
-            let command: Command = serde_json::from_str(s?.as_str())?;
-            match command {
-                Command::Set(s) => Ok(Some(s.1)),
-                _ => Ok(None),
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.read_value(&key.into())
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        // checked as part of the commit, so a concurrent remove can't also succeed
+        match self.commit(Command::Rm(key.into()), Some(Condition::Present)) {
+            Err(e) if e.is_condition_failed() => {
+                return Err(KvsError::from(KvsErrorKind::KeyNotFound))
             }
-        } else {
-            Ok(None)
+            result => result?,
         }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let commands = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => Command::Set((key, value)),
+                BatchOp::Remove(key) => Command::Rm(key),
+            })
+            .collect();
+        self.commit(Command::Batch(commands), None)?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let command = Command::Set((key.into(), value.into()));
+        self.commit(command, Some(Condition::Absent))?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let command = Command::Set((key.into(), value.into()));
+        self.commit(command, Some(Condition::Present))?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let condition = Condition::Equals(expected.into());
+        self.commit(Command::Set((key.into(), value.into())), Some(condition))?;
+        self.maybe_compact();
+        Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let _v = self
-            .get(key.clone())?
-            .ok_or_else(|| KvsError::from(KvsErrorKind::KeyNotFound))?;
-        let command = Command::Rm(key.clone());
-        let s: String = serde_json::to_string(&command)?;
-        let mut w = self.writer.lock().expect("remove: cant write");
-        w.write_fmt(format_args!("{}\n", s))?;
-        w.flush()?;
-        self.index
-            .write()
-            .expect("remove: cant remove")
-            .remove(key.as_str());
-        *self.next_pos.write().expect("remove: cant increment") += 1;
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let command = Command::SetExpiring((key.into(), value.into(), expiry_after(ttl)));
+        self.commit(command, None)?;
+        self.maybe_compact();
         Ok(())
     }
+
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Option<Duration>) -> Result<()> {
+        let key = key.into();
+        self.writer()?;
+        // rewrite the value with the new expiry, unless it changes meanwhile
+        loop {
+            let value = self
+                .read_value(&key)?
+                .ok_or_else(|| KvsError::from(KvsErrorKind::KeyNotFound))?;
+            let condition = Condition::Equals(value.clone());
+            let command = match ttl {
+                Some(ttl) => Command::SetExpiring((key.clone(), value, expiry_after(ttl))),
+                None => Command::Set((key.clone(), value)),
+            };
+            match self.commit(command, Some(condition)) {
+                Err(e) if e.is_condition_failed() => continue,
+                result => result?,
+            }
+            self.maybe_compact();
+            return Ok(());
+        }
+    }
+
+    fn expires_at(&self, key: impl Into<Vec<u8>>) -> Result<Option<SystemTime>> {
+        match self.index.read().unwrap().get(&key.into()) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => {
+                Ok(cmd_pos.expires_at.map(expiry_time))
+            }
+            _ => Err(KvsError::from(KvsErrorKind::KeyNotFound)),
+        }
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        self.read_range(range, limit)
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        self.take_snapshot()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let now = now_millis();
+        let keys = self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .filter(|x| !x.is_expired(now))
+            .count() as u64;
+        let segments = self.segments.lock().unwrap();
+        Ok(EngineStats {
+            keys,
+            log_bytes: segments.values().map(|x| x.size).sum(),
+            // keys expired but not swept yet are still counted as live
+            stale_bytes: Some(segments.values().map(|x| x.stale).sum()),
+            segments: Some(segments.len() as u64),
+            last_compaction: *self.last_compaction.lock().unwrap(),
+        })
+    }
+
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.write_checkpoint(&dest.into())
+    }
 }
@@ -0,0 +1,121 @@
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+const FILE_NAME: &str = "kvs.engine";
+const TEMP_FILE_NAME: &str = "kvs.engine.tmp";
+/// Data directory format written by this version.
+/// Version 2 added `encrypted`, which version 1 binaries ignore, so they
+/// could write records in the clear into a sealed store.
+const FORMAT_VERSION: u32 = 2;
+
+/// Engine that owns a data directory and the format of the directory,
+/// kept as JSON in its `kvs.engine` file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EngineMarker {
+    /// Engine name, as given to `kvs-server --engine`
+    pub engine: String,
+    /// Format version of the directory
+    pub format_version: u32,
+    /// Whether the records are encrypted. A store that never was has its
+    /// records read in the clear once when a key is first given, to seal
+    /// them; after that, records in the clear are rejected.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl EngineMarker {
+    /// Marker of `dir`.
+    /// A directory written before markers existed is recognized by the files
+    /// of its engine; an empty one has no marker.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        match File::open(dir.join(FILE_NAME)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(legacy_engine(dir).map(|engine| Self {
+                    engine: engine.to_owned(),
+                    format_version: FORMAT_VERSION,
+                    encrypted: false,
+                }))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record `engine` as the owner of `dir` unless the directory has one,
+    /// and raise the format version to this version's, so older versions
+    /// refuse the directory from now on. Fails like `check` otherwise.
+    pub fn claim(dir: &Path, engine: &str) -> Result<Self> {
+        let mut marker = Self::check(dir, engine)?.unwrap_or_else(|| Self {
+            engine: engine.to_owned(),
+            format_version: FORMAT_VERSION,
+            encrypted: false,
+        });
+        if marker.format_version < FORMAT_VERSION || !dir.join(FILE_NAME).is_file() {
+            marker.format_version = FORMAT_VERSION;
+            marker.write(dir)?;
+        }
+        Ok(marker)
+    }
+
+    /// Marker of `dir`, checked without writing anything.
+    /// Fails with `WrongEngine` if the directory belongs to another engine
+    /// than `engine`, and with `WrongFormat` if it was written by a newer
+    /// version.
+    pub fn check(dir: &Path, engine: &str) -> Result<Option<Self>> {
+        let marker = match Self::read(dir)? {
+            Some(marker) => marker,
+            None => return Ok(None),
+        };
+        if marker.engine != engine {
+            return Err(KvsError::from(KvsErrorKind::WrongEngine(marker.engine)));
+        }
+        if marker.format_version > FORMAT_VERSION {
+            return Err(KvsError::from(KvsErrorKind::WrongFormat(format!(
+                "format version {}",
+                marker.format_version
+            ))));
+        }
+        Ok(Some(marker))
+    }
+
+    /// Record in `dir` whether its records are encrypted
+    pub fn set_encrypted(&mut self, dir: &Path, encrypted: bool) -> Result<()> {
+        if self.encrypted == encrypted {
+            return Ok(());
+        }
+        self.encrypted = encrypted;
+        self.write(dir)
+    }
+
+    /// Replace the marker file in one rename, so it is never half written
+    fn write(&self, dir: &Path) -> Result<()> {
+        let temp = dir.join(TEMP_FILE_NAME);
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        std::fs::rename(temp, dir.join(FILE_NAME))?;
+        Ok(())
+    }
+}
+
+/// Engine whose files are in `dir`
+fn legacy_engine(dir: &Path) -> Option<&'static str> {
+    let has = |name: &str| dir.join(name).exists();
+    let has_log = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.path().extension() == Some("log".as_ref()));
+    if has("sled.store") {
+        Some("sled")
+    } else if has("kvs.store") || has_log {
+        Some("kvs")
+    } else {
+        None
+    }
+}
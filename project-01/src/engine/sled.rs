@@ -1,49 +1,469 @@
+use crate::batch::BatchOp;
+use crate::engine::{
+    expiry_after, expiry_time, is_empty_range, now_millis, EngineMarker, EngineStats, KvsSnapshot,
+    Pair,
+};
 use crate::error::{KvsError, KvsErrorKind};
-use crate::{KvsEngine, Result};
-use bstr::ByteSlice;
+use crate::lock::{self, DirLock};
+use crate::{KvsEngine, Result, WriteBatch};
 use sled;
-use std::path::PathBuf;
+use sled::{ConflictableTransactionResult, IVec, Transactional, TransactionalTree};
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
 
 /// Key value store by sled
+///
+/// Expiry times of keys live in a separate tree, which a background thread
+/// sweeps for expired keys.
+/// sled has no snapshots of its own, so a snapshot is an in-memory copy of
+/// the live data, taken while writes are held off. Taking one costs time
+/// and memory in proportion to the data; reads carry on meanwhile.
+/// A checkpoint holds off writes until it is copied, a page of keys at a
+/// time, so it shows one point in time.
+/// Like `KvStore`, it locks its directory for as long as any clone is alive.
+/// sled 0.31 can't open its files in its own read-only mode, which asks to
+/// create them without write access, and opening them normally writes a
+/// new snapshot file into the directory. A read-only engine therefore works
+/// on a copy of the files taken when it opens, which costs time and disk
+/// space in proportion to the data. Files being written can't be copied
+/// consistently, so unlike a read-only `KvStore`, which can look into a
+/// store in use, it refuses to open while an engine has the directory.
 #[derive(Clone)]
-pub struct SledKvsEngine(sled::Db);
+pub struct SledKvsEngine {
+    db: sled::Db,
+    /// Expiry time of each expiring key, in milliseconds since the Unix epoch
+    expiry: sled::Tree,
+    /// Held shared by writes and exclusively while taking a snapshot
+    gate: Arc<RwLock<()>>,
+    /// Stops the sweep thread once the last clone is dropped
+    _sweeper: Arc<Sweeper>,
+    /// `None` on read-only engines
+    _lock: Option<Arc<DirLock>>,
+    /// Copy of the files a read-only engine works on
+    copy: Option<Arc<TempDir>>,
+}
 
 const FILE_NAME: &str = "sled.store";
+const EXPIRY_TREE: &str = "expiry";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Keys copied by a checkpoint at a time
+const CHECKPOINT_PAGE_SIZE: usize = 1000;
+/// File of its directory that sled locks
+const SLED_LOCK_FILE: &str = "db";
+/// How long to wait for sled's lock left by an engine just dropped
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Expired key sweep thread, stopped by dropping the sender
+struct Sweeper(Option<(Sender<()>, JoinHandle<()>)>);
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.0.take() {
+            drop(stop);
+            let _ = handle.join();
+        }
+    }
+}
 
 impl SledKvsEngine {
     /// open kvs
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let mut p: PathBuf = path.into();
+        // sled creates its directory, so the lock has to as well
+        std::fs::create_dir_all(&p)?;
+        let lock = DirLock::acquire(&p)?;
         p.push(FILE_NAME);
-        let inner = sled::open(p)?;
-        Ok(Self(inner))
+        // sled's background writes keep its own lock for a while after the
+        // last handle of an engine is dropped; ours keeps everyone else out
+        lock::wait_until_unlocked(&p.join(SLED_LOCK_FILE), RELEASE_TIMEOUT)?;
+        Self::start(sled::open(p)?, Some(lock), None)
+    }
+
+    /// open kvs without creating or changing any file, on a copy of them.
+    /// Fails with `Locked` while another engine has the directory open.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let dir = path.into();
+        let p = dir.join(FILE_NAME);
+        if !p.is_dir() {
+            return Err(KvsError::from(KvsErrorKind::IO));
+        }
+        // keeps writers out until the copy is taken; the copy protects the
+        // files from the snapshot sled writes when it opens them
+        let _shared = DirLock::acquire_shared(&dir)?;
+        let copy = tempfile::tempdir()?;
+        copy_dir(&p, copy.path())?;
+        Self::start(sled::open(copy.path())?, None, Some(copy))
+    }
+
+    /// Engine on `db`, which holds the `lock` of its directory, or is
+    /// read-only and opened on a `copy`.
+    /// Only a writable engine sweeps expired keys.
+    fn start(db: sled::Db, lock: Option<DirLock>, copy: Option<TempDir>) -> Result<Self> {
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let gate = Arc::new(RwLock::new(()));
+
+        let mut sweeper = Sweeper(None);
+        if copy.is_none() {
+            let (stop, stopped) = mpsc::channel();
+            let (sweep_db, sweep_expiry, sweep_gate) = (db.clone(), expiry.clone(), gate.clone());
+            let handle = std::thread::spawn(move || {
+                while stopped.recv_timeout(SWEEP_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+                    // errors surface on the next foreground access
+                    let _ = sweep(&sweep_db, &sweep_expiry, &sweep_gate);
+                }
+            });
+            sweeper.0 = Some((stop, handle));
+        }
+        Ok(Self {
+            db,
+            expiry,
+            gate,
+            _sweeper: Arc::new(sweeper),
+            _lock: lock.map(Arc::new),
+            copy: copy.map(Arc::new),
+        })
+    }
+
+    /// Run `f` on the data and expiry trees as one transaction.
+    /// Reads don't pass the gate, so snapshots don't hold them off.
+    fn transaction<A>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    ) -> Result<A> {
+        Ok((&*self.db, &self.expiry).transaction(|(data, expiry)| f(data, expiry))?)
+    }
+
+    /// Run `f` as a transaction that writes, once no snapshot is being taken.
+    /// Fails with `ReadOnly` on a read-only engine.
+    fn write<A>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    ) -> Result<A> {
+        if self.copy.is_some() {
+            return Err(KvsError::from(KvsErrorKind::ReadOnly));
+        }
+        let _shared = self.gate.read().unwrap();
+        self.transaction(f)
+    }
+
+    /// The first `limit` pairs of `iter` that haven't expired
+    fn collect_live(&self, iter: sled::Iter, limit: usize) -> Result<Vec<Pair>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for entry in iter {
+            if pairs.len() == limit {
+                break;
+            }
+            let (key, value) = entry?;
+            match self.expiry.get(&key)? {
+                Some(expires_at) if decode_expiry(&expires_at) <= now => continue,
+                _ => pairs.push((key.to_vec(), value.to_vec())),
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Set value with key unless `condition` rejects its current value.
+    /// The key loses its expiry.
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: impl Fn(Option<IVec>) -> bool,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let written = self.write(|data, expiry| {
+            if !condition(live_value(data, expiry, &key)?) {
+                return Ok(false);
+            }
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if written {
+            Ok(())
+        } else {
+            Err(KvsError::from(KvsErrorKind::ConditionFailed))
+        }
     }
 }
 
 impl Drop for SledKvsEngine {
     fn drop(&mut self) {
-        self.0.flush().unwrap();
+        if self.copy.is_none() {
+            self.db.flush().unwrap();
+        }
+    }
+}
+
+/// Copy the files under `from` into `to`
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
     }
+    Ok(())
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+/// Value of `key`, unless it has expired
+fn live_value(
+    data: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<IVec>> {
+    match expiry.get(key)? {
+        Some(expires_at) if decode_expiry(&expires_at) <= now_millis() => Ok(None),
+        _ => Ok(data.get(key)?),
+    }
+}
+
+/// Remove the keys that have expired
+fn sweep(db: &sled::Db, expiry: &sled::Tree, gate: &RwLock<()>) -> Result<()> {
+    let now = now_millis();
+    for entry in expiry.iter() {
+        let (key, expires_at) = entry?;
+        if decode_expiry(&expires_at) > now {
+            continue;
+        }
+        let _shared = gate.read().unwrap();
+        (&**db, expiry).transaction(|(data, expiry)| {
+            // skip keys that were set again meanwhile
+            if expiry.get(&key)?.as_ref() == Some(&expires_at) {
+                data.remove(&key)?;
+                expiry.remove(&key)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.insert(key.as_bytes(), value.as_bytes())?;
+    type Snapshot = SledSnapshot;
+
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.write(|data, expiry| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        let value = self.transaction(|data, expiry| live_value(data, expiry, &key))?;
+        Ok(value.map(|x| x.to_vec()))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let removed = self.write(|data, expiry| {
+            if live_value(data, expiry, &key)?.is_none() {
+                return Ok(false);
+            }
+            data.remove(key.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if !removed {
+            return Err(KvsError::from(KvsErrorKind::KeyNotFound));
+        }
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.0
-            .get(key)?
-            .map(|x| x.to_str().map_err(Into::into).map(str::to_string))
-            .transpose()
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|data, expiry| {
+            for op in batch.ops.iter() {
+                let key = match op {
+                    BatchOp::Set(key, value) => {
+                        data.insert(key.as_slice(), value.as_slice())?;
+                        key
+                    }
+                    BatchOp::Remove(key) => {
+                        data.remove(key.as_slice())?;
+                        key
+                    }
+                };
+                expiry.remove(key.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.set_if(key, value, |current| current.is_none())
+    }
+
+    fn set_if_present(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.set_if(key, value, |current| current.is_some())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if !self.0.contains_key(key.as_str())? {
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let expected = expected.into();
+        self.set_if(key, value, |current| {
+            current.as_ref().map(|x| x.as_ref()) == Some(expected.as_slice())
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expires_at = expiry_after(ttl).to_be_bytes();
+        self.write(|data, expiry| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at[..])?;
+            Ok(())
+        })
+    }
+
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Option<Duration>) -> Result<()> {
+        let key = key.into();
+        let expires_at = ttl.map(|ttl| expiry_after(ttl).to_be_bytes());
+        let found = self.write(|data, expiry| {
+            if live_value(data, expiry, &key)?.is_none() {
+                return Ok(false);
+            }
+            match &expires_at {
+                Some(expires_at) => expiry.insert(key.as_slice(), &expires_at[..])?,
+                None => expiry.remove(key.as_slice())?,
+            };
+            Ok(true)
+        })?;
+        if !found {
             return Err(KvsError::from(KvsErrorKind::KeyNotFound));
         }
-        self.0.remove(key)?;
         Ok(())
     }
+
+    fn expires_at(&self, key: impl Into<Vec<u8>>) -> Result<Option<SystemTime>> {
+        let key = key.into();
+        let expires_at =
+            self.transaction(|data, expiry| match live_value(data, expiry, &key)? {
+                Some(_) => Ok(Some(expiry.get(&key)?.map(|x| decode_expiry(&x)))),
+                None => Ok(None),
+            })?;
+        let expires_at = expires_at.ok_or_else(|| KvsError::from(KvsErrorKind::KeyNotFound))?;
+        Ok(expires_at.map(expiry_time))
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.collect_live(self.db.range(range), limit)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        self.collect_live(self.db.scan_prefix(prefix.into()), limit)
+    }
+
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let _exclusive = self.gate.write().unwrap();
+        let data = self.collect_live(self.db.iter(), usize::MAX)?;
+        Ok(SledSnapshot {
+            data: data.into_iter().collect(),
+        })
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let now = now_millis();
+        let mut expired = 0;
+        for entry in self.expiry.iter() {
+            let (_, expires_at) = entry?;
+            if decode_expiry(&expires_at) <= now {
+                expired += 1;
+            }
+        }
+        Ok(EngineStats {
+            keys: (self.db.len() as u64).saturating_sub(expired),
+            log_bytes: self.db.size_on_disk()?,
+            // sled compacts on its own and doesn't report what it could reclaim
+            ..EngineStats::default()
+        })
+    }
+
+    fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        if self.copy.is_some() {
+            return Err(KvsError::from(KvsErrorKind::ReadOnly));
+        }
+        let dest = dest.into();
+        std::fs::create_dir(&dest)?;
+        let copy = SledKvsEngine::open(&dest)?;
+        // writes are held off until the whole copy is taken, so it shows
+        // one point in time; it is read a page at a time to bound memory
+        let _exclusive = self.gate.write().unwrap();
+        let mut start = Bound::Unbounded;
+        loop {
+            let range = (start, Bound::Unbounded);
+            let pairs = self.collect_live(self.db.range(range), CHECKPOINT_PAGE_SIZE)?;
+            let full = pairs.len() == CHECKPOINT_PAGE_SIZE;
+            let mut batch = sled::Batch::default();
+            let mut expiry = sled::Batch::default();
+            for (key, value) in &pairs {
+                batch.insert(key.as_slice(), value.as_slice());
+                if let Some(expires_at) = self.expiry.get(key)? {
+                    expiry.insert(key.as_slice(), expires_at);
+                }
+            }
+            copy.db.apply_batch(batch)?;
+            copy.expiry.apply_batch(expiry)?;
+            match pairs.into_iter().last() {
+                Some((key, _)) if full => start = Bound::Excluded(key),
+                _ => break,
+            }
+        }
+        drop(copy);
+        EngineMarker::claim(&dest, "sled")?;
+        Ok(())
+    }
+}
+
+/// Read-only copy of the live data of a `SledKvsEngine`, held in memory
+pub struct SledSnapshot {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(&key.into()).cloned())
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let pairs = self.data.range(range).take(limit);
+        Ok(pairs
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
 }
@@ -30,6 +30,25 @@ pub enum KvsErrorKind {
     Parse,
     #[fail(display = "Concurrent")]
     Concurrent,
+    #[fail(display = "Corruption")]
+    Corruption,
+    #[fail(display = "ConditionFailed")]
+    ConditionFailed,
+    #[fail(display = "Tampered")]
+    Tampered,
+    #[fail(display = "MissingKey")]
+    MissingKey,
+    #[fail(display = "Locked: the data directory is in use by another store")]
+    Locked,
+    #[fail(
+        display = "WrongEngine: the data directory belongs to the {} engine",
+        _0
+    )]
+    WrongEngine(String),
+    #[fail(display = "ReadOnly")]
+    ReadOnly,
+    #[fail(display = "Forbidden")]
+    Forbidden,
 }
 
 #[derive(Debug)]
@@ -41,6 +60,38 @@ impl KvsError {
     pub fn is_key_not_found(&self) -> bool {
         &KvsErrorKind::KeyNotFound == self.inner.get_context()
     }
+
+    pub fn is_corruption(&self) -> bool {
+        &KvsErrorKind::Corruption == self.inner.get_context()
+    }
+
+    pub fn is_condition_failed(&self) -> bool {
+        &KvsErrorKind::ConditionFailed == self.inner.get_context()
+    }
+
+    pub fn is_tampered(&self) -> bool {
+        &KvsErrorKind::Tampered == self.inner.get_context()
+    }
+
+    pub fn is_missing_key(&self) -> bool {
+        &KvsErrorKind::MissingKey == self.inner.get_context()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        &KvsErrorKind::Locked == self.inner.get_context()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        &KvsErrorKind::ReadOnly == self.inner.get_context()
+    }
+
+    pub fn is_encoding(&self) -> bool {
+        &KvsErrorKind::Encoding == self.inner.get_context()
+    }
+
+    pub fn is_wrong_engine(&self) -> bool {
+        matches!(self.inner.get_context(), KvsErrorKind::WrongEngine(_))
+    }
 }
 
 #[allow(dead_code)]
@@ -94,6 +145,15 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<sled::TransactionError<()>> for KvsError {
+    fn from(error: sled::TransactionError<()>) -> Self {
+        match error {
+            sled::TransactionError::Storage(e) => e.into(),
+            sled::TransactionError::Abort(()) => KvsError::from(KvsErrorKind::Engine),
+        }
+    }
+}
+
 impl From<bstr::Utf8Error> for KvsError {
     fn from(error: bstr::Utf8Error) -> Self {
         Self {
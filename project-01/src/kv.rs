@@ -1,132 +1,866 @@
+use crate::engine::{is_empty_range, now_millis};
 use crate::error::{KvsError, KvsErrorKind};
+use crate::lock::DirLock;
+pub use cipher::EncryptionKey;
+use commit::CommitQueue;
+pub(crate) use commit::Condition;
+use compaction::FinishedCompaction;
+use readers::{PooledReader, ReaderPool};
+pub use repair::{Damage, DamageKind, VerifyReport};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use slog::{error, o, warn, Logger};
+pub use snapshot::KvStoreSnapshot;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufRead, BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
-const FILE_NAME: &str = "kvs.store";
-const SLINK_EXT: &str = "slink";
+mod checkpoint;
+mod cipher;
+mod commit;
+mod compaction;
+mod hint;
+mod readers;
+mod record;
+mod repair;
+mod snapshot;
+
+const LEGACY_FILE_NAME: &str = "kvs.store";
+const LOG_EXT: &str = "log";
+const TEMP_EXT: &str = "tmp";
+const HINT_EXT: &str = "hint";
+const HINT_TEMP_EXT: &str = "hint-tmp";
+
+/// KvStore settings
+#[derive(Clone, Debug)]
+pub struct KvStoreConfig {
+    /// Compact when stale bytes in the log reach this size
+    pub compaction_threshold: u64,
+    /// Compact when this fraction of the log is stale
+    pub compaction_ratio: f64,
+    /// `compaction_ratio` is ignored while the log is smaller than this
+    pub compaction_min_size: u64,
+    /// Start a new segment once the current one reaches this size
+    pub segment_size: u64,
+    /// When writes are synced to disk
+    pub durability: Durability,
+    /// Logger for background work
+    pub logger: Logger,
+    /// How often expired keys are dropped from the index
+    pub sweep_interval: Duration,
+    /// Codec for values written by `set`
+    pub compression: Compression,
+    /// Values shorter than this are stored uncompressed
+    pub compression_threshold: usize,
+    /// Open without creating or changing any file; writes fail with `ReadOnly`
+    pub read_only: bool,
+    /// Key that encrypts new records; `None` writes them in the clear
+    pub encryption_key: Option<EncryptionKey>,
+    /// Retired keys, still accepted for reading old records
+    pub previous_keys: Vec<EncryptionKey>,
+    /// With a key set, still read records and hints written in the clear.
+    /// Only meant for encrypting an existing store once: `rekey` seals
+    /// every record, and the store is opened without it from then on.
+    pub accept_plaintext: bool,
+}
+
+impl KvStoreConfig {
+    /// Keys that can decrypt records, the current one first
+    pub(crate) fn decryption_keys(&self) -> impl Iterator<Item = &EncryptionKey> {
+        self.encryption_key.iter().chain(&self.previous_keys)
+    }
+
+    /// Whether data written in the clear must be rejected as forged
+    pub(crate) fn requires_sealed(&self) -> bool {
+        self.decryption_keys().next().is_some() && !self.accept_plaintext
+    }
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: 0.5,
+            compaction_min_size: 1024 * 1024,
+            segment_size: 4 * 1024 * 1024,
+            durability: Durability::Buffered,
+            logger: Logger::root(slog::Discard, o!()),
+            sweep_interval: Duration::from_secs(1),
+            compression: Compression::None,
+            compression_threshold: 1024,
+            read_only: false,
+            encryption_key: None,
+            previous_keys: Vec::new(),
+            accept_plaintext: false,
+        }
+    }
+}
 
 /// key value store
+///
+/// The log is split into numbered segment files named `<gen>.log`.
+/// Writes go to the newest segment, which rolls over at `segment_size`.
+/// Compaction copies live records of the oldest segments into new ones
+/// and deletes the old files, so every segment but the newest is immutable.
+/// Each segment written by compaction gets a `<gen>.hint` file listing its
+/// keys and their locations, which open loads instead of reading the segment.
+/// The index is ordered by key, so ranges of keys can be scanned.
+/// Expired keys are dropped from the index by a background sweep, and
+/// compaction reclaims their records.
+/// Snapshots pin the segments they read, so compaction doesn't affect them.
+/// A checkpoint pins every segment the same way and copies them, hint files
+/// included.
+/// Opening locks the directory, so no other store can use it until every
+/// clone is dropped. A read-only store takes no lock; it sees the log as it
+/// was when opened, and reads of records that the store owning the
+/// directory has compacted away since fail.
+/// Segment files opened for reads are pooled and shared by every clone.
 #[derive(Clone)]
 pub struct KvStore {
     pub(crate) path: Arc<PathBuf>,
-    pub(crate) next_pos: Arc<RwLock<usize>>,
-    pub(crate) writer: Arc<Mutex<BufWriter<File>>>,
-    pub(crate) index: Arc<RwLock<HashMap<String, usize>>>,
+    pub(crate) config: Arc<KvStoreConfig>,
+    pub(crate) segments: Arc<Mutex<BTreeMap<u64, SegmentInfo>>>,
+    /// `None` on read-only stores
+    pub(crate) writer: Option<Arc<Mutex<LogWriter>>>,
+    pub(crate) commits: Arc<CommitQueue>,
+    pub(crate) index: Arc<RwLock<BTreeMap<Vec<u8>, CommandPos>>>,
+    /// Segments older than this have been compacted away
+    pub(crate) safe_point: Arc<AtomicU64>,
+    /// When compaction last finished; after open, when the newest hint
+    /// file was written
+    pub(crate) last_compaction: Arc<Mutex<Option<SystemTime>>>,
+    /// Segment files open for reads
+    pub(crate) readers: Arc<ReaderPool>,
+    /// `None` on the handles given to background threads
+    pub(crate) background: Option<Arc<Background>>,
+    /// `None` on read-only stores
+    _lock: Option<Arc<DirLock>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// When writes reach the disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// fsync before each write returns
+    Always,
+    /// fsync from a background thread at this interval
+    Periodic(Duration),
+    /// leave it to the OS
+    Buffered,
+}
+
+/// How values are compressed in the log.
+/// Records of every codec can be read whatever the setting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// store values as they are
+    None,
+    /// LZ4 block format
+    Lz4,
+}
+
+/// Background threads of a store, joined once every user handle is dropped
+#[derive(Default)]
+pub(crate) struct Background {
+    compaction: Mutex<Option<JoinHandle<FinishedCompaction>>>,
+    compaction_done: Arc<AtomicBool>,
+    /// Periodic fsync thread, stopped by dropping the sender
+    syncer: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+    /// Periodic expired key sweep, stopped by dropping the sender
+    sweeper: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        // An uninstalled result is dropped; its temporary files are removed on the next open.
+        if let Some(handle) = self.compaction.get_mut().unwrap().take() {
+            let _ = handle.join();
+        }
+        for thread in &mut [&mut self.syncer, &mut self.sweeper] {
+            if let Some((stop, handle)) = thread.get_mut().unwrap().take() {
+                drop(stop);
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// Size and stale bytes of a segment
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SegmentInfo {
+    pub(crate) size: u64,
+    pub(crate) stale: u64,
+}
+
+/// Segment, byte offset and length of a serialized command in the log
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CommandPos {
+    pub(crate) gen: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    /// Expiry of the key set here, in milliseconds since the Unix epoch
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
+
+#[derive(Debug)]
 pub enum Command {
+    Set((Vec<u8>, Vec<u8>)),
+    /// Set that expires at the given time, in milliseconds since the Unix epoch
+    SetExpiring((Vec<u8>, Vec<u8>, u64)),
+    Rm(Vec<u8>),
+    Batch(Vec<Command>),
+}
+
+/// Command as written to JSON-line segments, which only held strings
+#[derive(Serialize, Deserialize, Debug)]
+enum JsonCommand {
     Set((String, String)),
     Rm(String),
 }
 
+impl From<JsonCommand> for Command {
+    fn from(command: JsonCommand) -> Self {
+        match command {
+            JsonCommand::Set((key, value)) => Command::Set((key.into(), value.into())),
+            JsonCommand::Rm(key) => Command::Rm(key.into()),
+        }
+    }
+}
+
+/// Appends commands to the newest segment
+pub(crate) struct LogWriter {
+    gen: u64,
+    pos: u64,
+    writer: BufWriter<File>,
+}
+
+impl LogWriter {
+    fn open(dir: &Path, gen: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(log_path(dir, gen))?;
+        let pos = file.metadata()?.len();
+        Ok(Self {
+            gen,
+            pos,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Buffer `command`; it is written out by the next flush
+    pub(crate) fn append(
+        &mut self,
+        command: &Command,
+        config: &KvStoreConfig,
+    ) -> Result<CommandPos> {
+        let record = record::encode(command, config);
+        self.writer.write_all(&record)?;
+        let cmd_pos = CommandPos {
+            gen: self.gen,
+            pos: self.pos,
+            len: record.len() as u64,
+            expires_at: None,
+        };
+        self.pos += cmd_pos.len;
+        Ok(cmd_pos)
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Drop the buffered records and carry on writing segment `gen` at
+    /// `len`, cutting off what follows, after a failed write left the file
+    /// out of step with `pos`
+    pub(crate) fn rewind(&mut self, dir: &Path, gen: u64, len: u64) -> Result<()> {
+        let failed = std::mem::replace(self, LogWriter::open(dir, gen)?);
+        // unlike dropping it, this doesn't write the buffer out
+        let _unwritten = failed.writer.into_parts();
+        self.writer.get_ref().set_len(len)?;
+        self.pos = len;
+        Ok(())
+    }
+}
+
 /// Result alias
 pub type Result<T> = std::result::Result<T, KvsError>;
 
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, LOG_EXT))
+}
+
+fn temp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, TEMP_EXT))
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, HINT_EXT))
+}
+
+fn hint_temp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, HINT_TEMP_EXT))
+}
+
+/// Segments in `dir` with the given extension, oldest first
+fn sorted_gen_list(dir: &Path, ext: &str) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new(ext)) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                gens.push(gen);
+            }
+        }
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+fn add_stale(segments: &mut BTreeMap<u64, SegmentInfo>, cmd_pos: CommandPos) {
+    if let Some(segment) = segments.get_mut(&cmd_pos.gen) {
+        segment.stale += cmd_pos.len;
+    }
+}
+
+/// Apply `command`, stored at `cmd_pos`, to `index`, counting the records
+/// it supersedes as stale.
+/// The index points at the records inside a batch, which read like any other.
+/// Those records are either all sealed or all plain, so the length of the
+/// batch tells which.
+fn apply(
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
+    segments: &mut BTreeMap<u64, SegmentInfo>,
+    command: Command,
+    cmd_pos: CommandPos,
+) {
+    match command {
+        Command::Set((key, _)) => {
+            if let Some(old) = index.insert(key, cmd_pos) {
+                add_stale(segments, old);
+            }
+        }
+        Command::SetExpiring((key, _, expires_at)) => {
+            let cmd_pos = CommandPos {
+                expires_at: Some(expires_at),
+                ..cmd_pos
+            };
+            if let Some(old) = index.insert(key, cmd_pos) {
+                add_stale(segments, old);
+            }
+        }
+        Command::Rm(key) => {
+            if let Some(old) = index.remove(&key) {
+                add_stale(segments, old);
+            }
+            add_stale(segments, cmd_pos);
+        }
+        Command::Batch(commands) => {
+            let header = CommandPos {
+                len: record::HEADER_LEN,
+                ..cmd_pos
+            };
+            add_stale(segments, header);
+            let plain_len: u64 = commands.iter().map(record::encoded_len).sum();
+            let overhead = if cmd_pos.len > record::HEADER_LEN + plain_len {
+                record::SEALED_OVERHEAD
+            } else {
+                0
+            };
+            let mut pos = cmd_pos.pos + record::HEADER_LEN;
+            for command in commands {
+                let len = record::encoded_len(&command) + overhead;
+                let sub_pos = CommandPos {
+                    pos,
+                    len,
+                    ..cmd_pos
+                };
+                apply(index, segments, command, sub_pos);
+                pos += len;
+            }
+        }
+    }
+}
+
+/// Value set for `key` by a command the index points at.
+/// A record of another key means the index was misled, by a forged hint
+/// file for instance.
+fn value_of(key: &[u8], command: Command) -> Result<Vec<u8>> {
+    match command {
+        Command::Set((k, value)) | Command::SetExpiring((k, value, _)) if k == key => Ok(value),
+        Command::Set(_) | Command::SetExpiring(_) => Err(KvsError::from(KvsErrorKind::Corruption)),
+        _ => Err(KvsError::from(KvsErrorKind::Index)),
+    }
+}
+
+/// Drop the keys that expired by `now` from `index`, counting their records as stale.
+/// Older records of those keys are stale already, so no tombstone is needed:
+/// compaction only ever drops a prefix of the oldest segments.
+fn remove_expired(
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
+    segments: &mut BTreeMap<u64, SegmentInfo>,
+    now: u64,
+) {
+    index.retain(|_, cmd_pos| {
+        if cmd_pos.is_expired(now) {
+            add_stale(segments, *cmd_pos);
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Make a rename or unlink in `dir` durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
 impl KvStore {
     /// Return new store
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let mut p: PathBuf = path.into();
-        p.push(FILE_NAME);
-        let file_path = p.as_path();
+        Self::open_with_config(path, KvStoreConfig::default())
+    }
 
-        if file_path.exists() & !file_path.is_file() {
-            Err(KvsError::from(KvsErrorKind::IO))
+    /// Return a store that only reads `path`.
+    /// It takes no lock, so it can look into a store in use, unlike a
+    /// read-only `SledKvsEngine`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let config = KvStoreConfig {
+            read_only: true,
+            ..KvStoreConfig::default()
+        };
+        Self::open_with_config(path, config)
+    }
+
+    /// Return new store with settings
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<Self> {
+        let dir: PathBuf = path.into();
+        if !dir.is_dir() {
+            return Err(KvsError::from(KvsErrorKind::IO));
+        }
+        let lock = if config.read_only {
+            None
         } else {
-            let file = Self::open_store_file_append_mode(file_path)?;
-            let log = BufReader::new(File::open(file_path)?)
-                .lines()
-                .map(|x| {
-                    x.map_err::<KvsError, _>(Into::into).and_then(|x| {
-                        serde_json::from_str(x.as_str()).map_err::<KvsError, _>(Into::into)
-                    })
-                })
-                .collect::<Result<Vec<Command>>>()?;
-            let s = Self {
-                path: Arc::new(p),
-                next_pos: Arc::new(RwLock::new(log.len())),
-                writer: Arc::new(Mutex::new(BufWriter::new(file))),
-                index: Arc::new(RwLock::new(Self::build_index(log.iter()))),
-            };
-            Ok(s)
+            Some(Arc::new(DirLock::acquire(&dir)?))
+        };
+
+        if !config.read_only {
+            // a crash during compaction can leave unfinished files behind
+            for gen in sorted_gen_list(&dir, TEMP_EXT)? {
+                std::fs::remove_file(temp_path(&dir, gen))?;
+            }
+            for gen in sorted_gen_list(&dir, HINT_TEMP_EXT)? {
+                std::fs::remove_file(hint_temp_path(&dir, gen))?;
+            }
+        }
+        Self::upgrade_legacy_store(&dir, &config)?;
+
+        let gens = sorted_gen_list(&dir, LOG_EXT)?;
+        for gen in sorted_gen_list(&dir, HINT_EXT)? {
+            if gens.binary_search(&gen).is_err() && !config.read_only {
+                std::fs::remove_file(hint_path(&dir, gen))?;
+            }
+        }
+        let mut index = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+        for &gen in &gens {
+            if Self::load_hint(&dir, gen, &mut index, &mut segments, &config)? {
+                continue;
+            }
+            // only the newest segment can end in an interrupted append
+            let tail = Some(&gen) == gens.last();
+            Self::load(&dir, gen, &mut index, &mut segments, &config, tail)?;
         }
+        remove_expired(&mut index, &mut segments, now_millis());
+        let newest = gens.last().cloned().unwrap_or(1);
+        let writer = if config.read_only {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(LogWriter::open(&dir, newest)?)))
+        };
+        segments.entry(newest).or_default();
+        let safe_point = *segments.keys().next().unwrap();
+        let last_compaction = gens
+            .iter()
+            .filter_map(|&gen| std::fs::metadata(hint_path(&dir, gen)).ok())
+            .filter_map(|metadata| metadata.modified().ok())
+            .max();
+
+        let store = Self {
+            path: Arc::new(dir),
+            config: Arc::new(config),
+            segments: Arc::new(Mutex::new(segments)),
+            writer,
+            commits: Arc::new(CommitQueue::default()),
+            index: Arc::new(RwLock::new(index)),
+            safe_point: Arc::new(AtomicU64::new(safe_point)),
+            last_compaction: Arc::new(Mutex::new(last_compaction)),
+            readers: Arc::new(ReaderPool::default()),
+            background: Some(Arc::new(Background::default())),
+            _lock: lock,
+        };
+        if let Some(background) = &store.background {
+            if let (Durability::Periodic(interval), false) =
+                (store.config.durability, store.config.read_only)
+            {
+                // sync once more on the way out
+                *background.syncer.lock().unwrap() =
+                    Some(store.start_periodic(interval, true, |store| {
+                        let writer = store.writer.as_ref().unwrap();
+                        if let Err(e) = writer.lock().unwrap().sync() {
+                            error!(store.config.logger, "fail to sync: {}", e);
+                        }
+                    }));
+            }
+            let interval = store.config.sweep_interval;
+            *background.sweeper.lock().unwrap() =
+                Some(store.start_periodic(interval, false, KvStore::sweep_expired));
+        }
+        Ok(store)
     }
 
-    fn open_store_file_append_mode(path: &Path) -> Result<File> {
-        OpenOptions::new()
-            .append(true)
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(path)
-            .map_err(Into::into)
+    /// Run `task` on a background thread every `interval`, and once more on
+    /// the way out if `last_run` is set, until the returned sender is dropped
+    fn start_periodic(
+        &self,
+        interval: Duration,
+        last_run: bool,
+        task: impl Fn(&KvStore) + Send + 'static,
+    ) -> (Sender<()>, JoinHandle<()>) {
+        let store = KvStore {
+            background: None,
+            ..self.clone()
+        };
+        let (stop, stopped) = mpsc::channel();
+        let handle = std::thread::spawn(move || loop {
+            let last = stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout);
+            if last && !last_run {
+                break;
+            }
+            task(&store);
+            if last {
+                break;
+            }
+        });
+        (stop, handle)
+    }
+
+    /// Drop expired keys from the index.
+    /// Their records are reclaimed by compaction.
+    pub(crate) fn sweep_expired(&self) {
+        let now = now_millis();
+        if !self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .any(|x| x.is_expired(now))
+        {
+            return;
+        }
+        let mut index = self.index.write().unwrap();
+        let mut segments = self.segments.lock().unwrap();
+        remove_expired(&mut index, &mut segments, now);
     }
 
-    fn build_index<'a>(iter: impl Iterator<Item = &'a Command>) -> HashMap<String, usize> {
-        iter.enumerate().fold(HashMap::new(), |mut acc, x| {
-            match x.1 {
-                Command::Set(s) => {
-                    acc.insert(s.0.clone(), x.0);
+    /// Stores written before segment files existed keep everything in
+    /// `kvs.store`; it becomes the first segment.
+    /// Segments written before the binary format hold one JSON command per
+    /// line; they are rewritten as records.
+    /// A read-only store can't upgrade, so it fails with `WrongFormat`.
+    fn upgrade_legacy_store(dir: &Path, config: &KvStoreConfig) -> Result<()> {
+        let legacy_format = || {
+            let message = "written by an older version, open it writable once".to_owned();
+            Err(KvsError::from(KvsErrorKind::WrongFormat(message)))
+        };
+        let legacy = dir.join(LEGACY_FILE_NAME);
+        if legacy.is_file() && sorted_gen_list(dir, LOG_EXT)?.is_empty() {
+            if config.read_only {
+                return legacy_format();
+            }
+            std::fs::rename(legacy, log_path(dir, 1))?;
+            sync_dir(dir)?;
+        }
+        for gen in sorted_gen_list(dir, LOG_EXT)? {
+            let mut first = [0; 1];
+            let read = File::open(log_path(dir, gen))?.read(&mut first)?;
+            if read == 1 && first[0] == b'{' {
+                if config.read_only {
+                    return legacy_format();
                 }
-                Command::Rm(r) => {
-                    acc.remove(r.as_str());
+                Self::upgrade_json_segment(dir, gen, config)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// An unterminated last line that fails to parse was cut off by a crash
+    /// and is dropped. The records are sealed if `config` has a key.
+    fn upgrade_json_segment(dir: &Path, gen: u64, config: &KvStoreConfig) -> Result<()> {
+        let mut reader = BufReader::new(File::open(log_path(dir, gen))?);
+        let mut writer = BufWriter::new(File::create(temp_path(dir, gen))?);
+        let mut pos = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line)?;
+            if len == 0 {
+                break;
+            }
+            let command: JsonCommand = match serde_json::from_str(line.as_str()) {
+                Ok(command) => command,
+                Err(_) if !line.ends_with('\n') => {
+                    warn!(config.logger, "discard torn record"; "segment" => gen, "offset" => pos, "bytes" => len);
+                    break;
                 }
+                Err(e) => return Err(e.into()),
+            };
+            let key = config.encryption_key.as_ref();
+            let record = record::encode_with(&command.into(), Compression::None, 0, key);
+            writer.write_all(&record)?;
+            pos += len;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(temp_path(dir, gen), log_path(dir, gen))?;
+        sync_dir(dir)
+    }
+
+    /// Load the keys of a compacted segment from its hint file.
+    /// Returns `false` if there is no usable hint and the segment has to be replayed.
+    fn load_hint(
+        dir: &Path,
+        gen: u64,
+        index: &mut BTreeMap<Vec<u8>, CommandPos>,
+        segments: &mut BTreeMap<u64, SegmentInfo>,
+        config: &KvStoreConfig,
+    ) -> Result<bool> {
+        let path = hint_path(dir, gen);
+        if !path.is_file() {
+            return Ok(false);
+        }
+        let size = std::fs::metadata(log_path(dir, gen))?.len();
+        let entries = match hint::read(&path, gen, config)? {
+            Some((log_size, entries)) if log_size == size => entries,
+            _ => {
+                warn!(config.logger, "ignore damaged hint file"; "segment" => gen);
+                return Ok(false);
             }
-            acc
-        })
+        };
+        segments.insert(gen, SegmentInfo { size, stale: 0 });
+        for (key, cmd_pos) in entries {
+            if let Some(old) = index.insert(key, cmd_pos) {
+                add_stale(segments, old);
+            }
+        }
+        Ok(true)
     }
 
-    fn temp_file_name_for_slink(&self) -> PathBuf {
-        let mut temp = (*self.path).clone();
-        temp.set_extension(SLINK_EXT);
-        temp
+    /// Replay one segment into `index`, counting sizes and stale bytes into `segments`.
+    /// With `tail` set, a torn record at the end of the segment is
+    /// truncated away and logged; corruption anywhere else is an error.
+    fn load(
+        dir: &Path,
+        gen: u64,
+        index: &mut BTreeMap<Vec<u8>, CommandPos>,
+        segments: &mut BTreeMap<u64, SegmentInfo>,
+        config: &KvStoreConfig,
+        tail: bool,
+    ) -> Result<()> {
+        let file = File::open(log_path(dir, gen))?;
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut pos = 0;
+        segments.insert(gen, SegmentInfo::default());
+        loop {
+            let (command, len) = match record::read_next(&mut reader, size - pos, config) {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) if tail && e.is_corruption() => {
+                    Self::truncate_torn_tail(dir, gen, pos, config)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let cmd_pos = CommandPos {
+                gen,
+                pos,
+                len,
+                expires_at: None,
+            };
+            apply(index, segments, command, cmd_pos);
+            pos += len;
+            segments.get_mut(&gen).unwrap().size = pos;
+        }
+        Ok(())
     }
 
-    fn temp_file_for_slink(&self) -> Result<File> {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(self.temp_file_name_for_slink())
-            .map_err::<KvsError, _>(Into::into)
+    /// Cut the segment back to `pos` if everything after it is a torn record,
+    /// and no intact record follows it as one would a damaged length.
+    /// A read-only store leaves the record alone; it may be an append in
+    /// progress.
+    fn truncate_torn_tail(dir: &Path, gen: u64, pos: u64, config: &KvStoreConfig) -> Result<()> {
+        let path = log_path(dir, gen);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!config.read_only)
+            .open(path)?;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest)?;
+        if !record::is_torn_tail(&rest) || repair::is_followed_by_record(&rest, config) {
+            return Err(KvsError::from(KvsErrorKind::Corruption));
+        }
+        if config.read_only {
+            return Ok(());
+        }
+        warn!(config.logger, "discard torn record"; "segment" => gen, "offset" => pos, "bytes" => rest.len());
+        file.set_len(pos)?;
+        file.sync_all()?;
+        Ok(())
     }
 
-    fn ref_numbers_by_index(&self) -> Vec<usize> {
-        self.index.read().unwrap().values().map(|&x| x).collect()
+    /// Writer of the newest segment; fails with `ReadOnly` on a read-only store
+    pub(crate) fn writer(&self) -> Result<&Mutex<LogWriter>> {
+        self.writer
+            .as_deref()
+            .ok_or_else(|| KvsError::from(KvsErrorKind::ReadOnly))
     }
 
-    fn create_slink_file(&self) -> Result<usize> {
-        let mut reader = BufReader::new(File::open(self.path.as_path())?).lines();
-        let temp_file = self.temp_file_for_slink()?;
-        let mut writer = BufWriter::new(temp_file);
-        let indies = self.ref_numbers_by_index();
-        let len = indies.len();
+    /// Append `command` to the newest segment, rolling over to a new one when it is full
+    pub(crate) fn append(&self, w: &mut LogWriter, command: &Command) -> Result<CommandPos> {
+        let cmd_pos = w.append(command, &self.config)?;
+        let mut segments = self.segments.lock().unwrap();
+        segments.entry(cmd_pos.gen).or_default().size = w.pos;
+        if w.pos >= self.config.segment_size {
+            self.switch_segment(w, w.gen + 1)?;
+            segments.insert(w.gen, SegmentInfo::default());
+        }
+        Ok(cmd_pos)
+    }
 
-        for number in indies {
-            let l = reader
-                .nth(number)
-                .ok_or_else(|| KvsError::from(KvsErrorKind::Index))?;
-            println!("{}", number);
-            l.and_then(|x| writeln!(writer, "{}", x))?;
+    /// Continue writing in segment `gen`.
+    /// Unless writes are left to the OS, the old segment is synced first and
+    /// the new one is made durable.
+    pub(crate) fn switch_segment(&self, w: &mut LogWriter, gen: u64) -> Result<()> {
+        let durable = self.config.durability != Durability::Buffered;
+        if durable {
+            w.sync()?;
+        } else {
+            w.writer.flush()?;
         }
+        *w = LogWriter::open(&self.path, gen)?;
+        if durable {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
 
-        Ok(len)
+    pub(crate) fn log_path(&self, gen: u64) -> PathBuf {
+        log_path(self.path.as_path(), gen)
     }
 
-    /// Slink log file
-    pub fn slink(&mut self) -> Result<()> {
-        let len = self.create_slink_file()?;
+    pub(crate) fn hint_path(&self, gen: u64) -> PathBuf {
+        hint_path(self.path.as_path(), gen)
+    }
 
-        let file_path = self.temp_file_name_for_slink();
-        std::fs::copy(file_path.as_path(), self.path.as_path())?;
-        std::fs::remove_file(file_path.as_path())?;
+    /// Open segment `gen` for reading, or reuse an idle open file of it.
+    /// Files of compacted segments are closed on the way.
+    /// Call it while holding the index, so compaction can't delete `gen` first.
+    pub(crate) fn reader(&self, gen: u64) -> Result<PooledReader<'_>> {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.readers.get(gen, &self.log_path(gen), safe_point)
+    }
 
-        *self.next_pos.write().unwrap() = len;
-        Ok(())
+    pub(crate) fn read_command(
+        reader: &mut File,
+        cmd_pos: CommandPos,
+        config: &KvStoreConfig,
+    ) -> Result<Command> {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut record = vec![0; cmd_pos.len as usize];
+        reader.read_exact(&mut record)?;
+        record::decode(&record, config)
+    }
+
+    /// Current value of `key`
+    pub(crate) fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(now_millis()) => {
+                // an open file stays readable after compaction deletes it
+                let mut reader = self.reader(cmd_pos.gen)?;
+                drop(index);
+                value_of(
+                    key,
+                    KvStore::read_command(&mut reader, cmd_pos, &self.config)?,
+                )
+                .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Keys in `range` with their values, in key order, at most `limit` of them
+    pub(crate) fn read_range(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        // hold the index, so compaction can't delete the segments being read
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for (key, &cmd_pos) in index
+            .range(range)
+            .filter(|(_, x)| !x.is_expired(now))
+            .take(limit)
+        {
+            let mut reader = self.reader(cmd_pos.gen)?;
+            let value = value_of(
+                key,
+                KvStore::read_command(&mut reader, cmd_pos, &self.config)?,
+            )?;
+            pairs.push((key.clone(), value));
+        }
+        Ok(pairs)
+    }
+
+    /// Whether `key` is set and not expired
+    pub(crate) fn contains_key(&self, key: &[u8]) -> bool {
+        match self.index.read().unwrap().get(key) {
+            Some(cmd_pos) => !cmd_pos.is_expired(now_millis()),
+            None => false,
+        }
     }
 }
@@ -0,0 +1,57 @@
+use super::{hint_path, log_path, sync_dir, KvStore};
+use crate::engine::EngineMarker;
+use crate::Result;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Segment to copy: its number, the size it had when the checkpoint was
+/// taken, and its open log and hint files
+type PinnedSegment = (u64, u64, File, Option<File>);
+
+impl KvStore {
+    /// Copy the log as it is now into the new directory `dest`.
+    /// Only opening the segment files holds off writes and compaction; the
+    /// copy is taken from the open files afterwards, up to the sizes they had,
+    /// so appends and compaction deleting them don't change it.
+    pub(crate) fn write_checkpoint(&self, dest: &Path) -> Result<()> {
+        self.writer()?;
+        std::fs::create_dir(dest)?;
+        for (gen, size, log, hint) in self.pin_segments()? {
+            copy_file(log.take(size), &log_path(dest, gen))?;
+            if let Some(hint) = hint {
+                copy_file(hint, &hint_path(dest, gen))?;
+            }
+        }
+        sync_dir(dest)?;
+        let encrypted = self.config.encryption_key.is_some() && !self.config.accept_plaintext;
+        EngineMarker::claim(dest, "kvs")?.set_encrypted(dest, encrypted)?;
+        Ok(())
+    }
+
+    fn pin_segments(&self) -> Result<Vec<PinnedSegment>> {
+        let mut writer = self.writer()?.lock().unwrap();
+        writer.writer.flush()?;
+        // compaction removes segments while it holds the index
+        let _index = self.index.read().unwrap();
+        let segments = self.segments.lock().unwrap();
+        segments
+            .iter()
+            .map(|(&gen, segment)| {
+                let hint = match File::open(self.hint_path(gen)) {
+                    Ok(hint) => Some(hint),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                };
+                Ok((gen, segment.size, File::open(self.log_path(gen))?, hint))
+            })
+            .collect()
+    }
+}
+
+fn copy_file(mut from: impl Read, to: &Path) -> Result<()> {
+    let mut file = File::create(to)?;
+    std::io::copy(&mut from, &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
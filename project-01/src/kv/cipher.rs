@@ -0,0 +1,103 @@
+//! Authenticated encryption of records and hint files with XChaCha20-Poly1305
+//!
+//! ```text
+//! | key_id: [u8; 4] | nonce: [u8; 24] | ciphertext | tag: [u8; 16] |
+//! ```
+//!
+//! Nonces are random, which 24 bytes make safe. The key id is derived from
+//! the key by the cipher itself, so it tells which key sealed the data
+//! without revealing anything about the key.
+
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Bytes added by `seal`
+pub(crate) const OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+/// 256-bit key that encrypts a `KvStore` at rest
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+    id: [u8; KEY_ID_LEN],
+}
+
+impl EncryptionKey {
+    /// Key made of `bytes`
+    pub fn new(bytes: [u8; 32]) -> Self {
+        let cipher = XChaCha20Poly1305::new(&bytes.into());
+        let payload = Payload {
+            msg: &[],
+            aad: b"kvs key id",
+        };
+        let tag = cipher.encrypt(&XNonce::default(), payload).unwrap();
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&tag[..KEY_ID_LEN]);
+        Self { cipher, id }
+    }
+
+    /// Key written as 64 hex digits; surrounding whitespace is ignored
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        let invalid = || KvsError::from(KvsErrorKind::InvalidArgument);
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self::new(bytes))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey({:02x?})", self.id)
+    }
+}
+
+/// Encrypt `plaintext` with `key`, authenticating `aad` along with it
+pub(crate) fn seal(key: &EncryptionKey, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let ciphertext = key.cipher.encrypt(&nonce, payload).unwrap();
+    [&key.id[..], &nonce[..], &ciphertext[..]].concat()
+}
+
+/// Whether `sealed` was sealed with `key`
+pub(crate) fn is_sealed_with(key: &EncryptionKey, sealed: &[u8]) -> bool {
+    sealed.get(..KEY_ID_LEN) == Some(&key.id[..])
+}
+
+/// Decrypt `sealed` with whichever of `keys` sealed it.
+/// Fails with `MissingKey` if none did and with `Tampered` if it or `aad`
+/// changed since.
+pub(crate) fn open<'a>(
+    mut keys: impl Iterator<Item = &'a EncryptionKey>,
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    if sealed.len() < OVERHEAD {
+        return Err(KvsError::from(KvsErrorKind::Tampered));
+    }
+    let key = keys
+        .find(|key| is_sealed_with(key, sealed))
+        .ok_or_else(|| KvsError::from(KvsErrorKind::MissingKey))?;
+    let nonce = XNonce::from_slice(&sealed[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+    let payload = Payload {
+        msg: &sealed[KEY_ID_LEN + NONCE_LEN..],
+        aad,
+    };
+    key.cipher
+        .decrypt(nonce, payload)
+        .map_err(|_| KvsError::from(KvsErrorKind::Tampered))
+}
@@ -0,0 +1,301 @@
+use super::{apply, Command, CommandPos, Durability, KvStore, LogWriter};
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::{Condvar, Mutex};
+
+/// Writes waiting to be committed together
+#[derive(Default)]
+pub(crate) struct CommitQueue {
+    state: Mutex<CommitState>,
+    committed: Condvar,
+}
+
+/// What the current value of a key must be for a write to go ahead
+pub(crate) enum Condition {
+    Absent,
+    Present,
+    Equals(Vec<u8>),
+}
+
+#[derive(Default)]
+struct CommitState {
+    pending: Vec<(Command, Option<Condition>)>,
+    /// Sequence number of the last queued write
+    queued: u64,
+    /// Sequence number of the last committed write
+    committed: u64,
+    /// A writer is committing a batch
+    leader: bool,
+    /// Committed writes that failed, or whose condition didn't hold
+    failed: HashMap<u64, KvsError>,
+}
+
+/// Values written earlier in the batch, `None` for removed keys
+type Written = HashMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// For each write of a batch, `None` if it went ahead, or why it was skipped
+type Skipped = Vec<Option<KvsError>>;
+
+/// Why each write of a batch was skipped, and where the written ones are
+type Appended = (Skipped, Vec<(Command, CommandPos)>);
+
+impl KvStore {
+    /// Append `command` to the log and apply it to the index.
+    ///
+    /// Concurrent writers are committed in batches: the first writer to find
+    /// no batch in progress appends everything queued so far with a single
+    /// flush and sync, then wakes the others. Returns once the record is as
+    /// durable as `durability` asks for.
+    /// With a `condition`, the write is skipped with a `ConditionFailed`
+    /// error unless the condition holds when its turn comes, or with the
+    /// error reading the current value if that fails; the rest of the batch
+    /// goes ahead either way.
+    pub(crate) fn commit(&self, command: Command, condition: Option<Condition>) -> Result<()> {
+        self.writer()?;
+        let queue = &self.commits;
+        let mut state = queue.state.lock().unwrap();
+        state.pending.push((command, condition));
+        state.queued += 1;
+        let seq = state.queued;
+
+        while state.leader && state.committed < seq {
+            state = queue.committed.wait(state).unwrap();
+        }
+        if state.committed >= seq {
+            return match state.failed.remove(&seq) {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
+
+        // lead a batch, which includes this write
+        state.leader = true;
+        let batch = std::mem::take(&mut state.pending);
+        let first = state.committed + 1;
+        let last = state.queued;
+        drop(state);
+        let result = self.write_batch(batch);
+
+        let mut state = queue.state.lock().unwrap();
+        state.leader = false;
+        let result = match result {
+            Ok(skipped) => {
+                for (seq, e) in (first..=last).zip(skipped) {
+                    if let Some(e) = e {
+                        state.failed.insert(seq, e);
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for seq in first..=last {
+                    state.failed.insert(seq, KvsError::from(KvsErrorKind::IO));
+                }
+                Err(e)
+            }
+        };
+        state.committed = last;
+        queue.committed.notify_all();
+        match state.failed.remove(&seq) {
+            Some(e) if result.is_ok() => Err(e),
+            _ => result,
+        }
+    }
+
+    /// Write the commands whose condition holds.
+    /// Returns why each one that wasn't written was skipped. On failure none
+    /// of them is: the log is cut back to where the batch started.
+    fn write_batch(&self, batch: Vec<(Command, Option<Condition>)>) -> Result<Skipped> {
+        let mut w = self.writer()?.lock().expect("commit: cant write");
+        let (start_gen, start_pos) = (w.gen, w.pos);
+        let (skipped, positions) = match self.write_records(&mut w, batch) {
+            Ok(written) => written,
+            Err(e) => {
+                self.roll_back(&mut w, start_gen, start_pos)?;
+                return Err(e);
+            }
+        };
+
+        let mut index = self.index.write().expect("commit: cant update index");
+        let mut segments = self.segments.lock().unwrap();
+        for (command, cmd_pos) in positions {
+            apply(&mut index, &mut segments, command, cmd_pos);
+        }
+        Ok(skipped)
+    }
+
+    /// Undo the appends of a failed batch that started at `pos` of segment
+    /// `gen`: cut that segment back to it, and remove the segments the batch
+    /// rolled over into. Compaction only numbers its outputs below the
+    /// writer's segment, so every newer one is the batch's.
+    fn roll_back(&self, w: &mut LogWriter, gen: u64, pos: u64) -> Result<()> {
+        let last = w.gen;
+        w.rewind(&self.path, gen, pos)?;
+        let mut segments = self.segments.lock().unwrap();
+        for newer in gen + 1..=last {
+            segments.remove(&newer);
+            match std::fs::remove_file(self.log_path(newer)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        segments.entry(gen).or_default().size = pos;
+        Ok(())
+    }
+
+    /// Append and flush the commands whose condition holds.
+    /// Returns why each one that wasn't written was skipped, and where the
+    /// written ones are.
+    fn write_records(
+        &self,
+        w: &mut LogWriter,
+        batch: Vec<(Command, Option<Condition>)>,
+    ) -> Result<Appended> {
+        let mut written = Written::new();
+        let mut skipped = Vec::with_capacity(batch.len());
+        let mut positions = Vec::with_capacity(batch.len());
+        for (command, condition) in batch {
+            let skip = match &condition {
+                Some(condition) => match self.condition_holds(&command, condition, &written) {
+                    Ok(true) => None,
+                    Ok(false) => Some(KvsError::from(KvsErrorKind::ConditionFailed)),
+                    Err(e) => Some(e),
+                },
+                None => None,
+            };
+            if skip.is_none() {
+                record_written(&mut written, &command);
+                let cmd_pos = self.append(w, &command)?;
+                positions.push((command, cmd_pos));
+            }
+            skipped.push(skip);
+        }
+        w.writer.flush()?;
+        if self.config.durability == Durability::Always {
+            w.sync()?;
+        }
+        Ok((skipped, positions))
+    }
+
+    fn condition_holds(
+        &self,
+        command: &Command,
+        condition: &Condition,
+        written: &Written,
+    ) -> Result<bool> {
+        let key = match command {
+            Command::Set((key, _)) | Command::SetExpiring((key, _, _)) | Command::Rm(key) => key,
+            Command::Batch(_) => return Err(KvsError::from(KvsErrorKind::InvalidArgument)),
+        };
+        let present = match written.get(key) {
+            Some(value) => value.is_some(),
+            None => self.contains_key(key),
+        };
+        Ok(match condition {
+            Condition::Absent => !present,
+            Condition::Present => present,
+            Condition::Equals(expected) => {
+                let current = match written.get(key) {
+                    Some(value) => value.clone(),
+                    None => self.read_value(key)?,
+                };
+                current.as_ref() == Some(expected)
+            }
+        })
+    }
+}
+
+fn record_written(written: &mut Written, command: &Command) {
+    match command {
+        Command::Set((key, value)) | Command::SetExpiring((key, value, _)) => {
+            written.insert(key.clone(), Some(value.clone()));
+        }
+        Command::Rm(key) => {
+            written.insert(key.clone(), None);
+        }
+        Command::Batch(commands) => {
+            for command in commands {
+                record_written(written, command);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Condition};
+    use crate::{KvStore, KvStoreConfig, KvsEngine};
+    use tempfile::TempDir;
+
+    fn set(key: &str) -> (Command, Option<Condition>) {
+        (Command::Set((key.into(), b"1".to_vec())), None)
+    }
+
+    // A condition that can't be read should fail only its own write
+    #[test]
+    fn unreadable_condition_skips_one_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("x", "1").unwrap();
+
+        // damage the record of x, so checking the condition on it fails
+        let log = temp_dir.path().join("1.log");
+        let bytes = std::fs::read(&log).unwrap();
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&log, damaged).unwrap();
+        let batch = vec![
+            set("a"),
+            (
+                Command::Set((b"x".to_vec(), b"2".to_vec())),
+                Some(Condition::Equals(b"1".to_vec())),
+            ),
+            set("b"),
+        ];
+        let skipped = store.write_batch(batch).unwrap();
+        assert!(skipped[0].is_none());
+        assert!(skipped[1].as_ref().unwrap().is_corruption());
+        assert!(skipped[2].is_none());
+        let mut repaired = std::fs::read(&log).unwrap();
+        repaired[..bytes.len()].copy_from_slice(&bytes);
+        std::fs::write(&log, repaired).unwrap();
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("b").unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("x").unwrap(), Some("1".to_owned()));
+    }
+
+    // A batch that fails after rolling over to new segments should leave
+    // none of its records behind in them or in the segment it started in
+    #[test]
+    fn failed_batch_across_segments() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            segment_size: 1,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap();
+        // x fills segment 1 and the batch starts in segment 2
+        store.set("x", "1").unwrap();
+
+        // a fills segment 2, b segment 3, and segment 4 can't be created
+        let blocker = temp_dir.path().join("4.log");
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(store.write_batch(vec![set("a"), set("b")]).is_err());
+        std::fs::remove_dir(&blocker).unwrap();
+
+        assert_eq!(store.writer().unwrap().lock().unwrap().pos, 0);
+        assert!(!temp_dir.path().join("3.log").exists());
+        store.set("c", "1").unwrap();
+        drop(store);
+        let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.get("b").unwrap(), None);
+        assert_eq!(store.get("c").unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("x").unwrap(), Some("1".to_owned()));
+    }
+}
@@ -0,0 +1,379 @@
+use super::cipher::EncryptionKey;
+use super::{
+    add_stale, hint, hint_temp_path, record, sync_dir, temp_path, CommandPos, KvStore, SegmentInfo,
+};
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use slog::{error, info};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// New location of compacted records, keyed by (segment, offset)
+type Moved = HashMap<(u64, u64), CommandPos>;
+
+/// Compaction whose inputs are picked but not copied yet
+struct PendingCompaction {
+    inputs: Vec<u64>,
+    live: Vec<(Vec<u8>, CommandPos)>,
+    outputs: Range<u64>,
+    /// Temporary segment and hint file of each output
+    temps: Vec<(File, File)>,
+}
+
+/// Copied compaction output waiting to be installed
+pub(crate) struct FinishedCompaction {
+    inputs: Vec<u64>,
+    outputs: Range<u64>,
+    result: Result<(Moved, BTreeMap<u64, SegmentInfo>)>,
+}
+
+impl KvStore {
+    /// Slink log file
+    pub fn slink(&mut self) -> Result<()> {
+        self.compact_now(false)
+    }
+
+    /// Compact every segment, which rewrites all records under the current
+    /// encryption key. Afterwards the previous keys are no longer needed.
+    pub fn rekey(&mut self) -> Result<()> {
+        self.compact_now(true)
+    }
+
+    /// Compact in the foreground, after installing any running background
+    /// compaction. With `all` set every segment is compacted.
+    fn compact_now(&mut self, all: bool) -> Result<()> {
+        match &self.background {
+            Some(background) => {
+                let mut running = background.compaction.lock().unwrap();
+                if let Some(handle) = running.take() {
+                    let finished = handle.join().expect("slink: compaction thread panicked");
+                    self.install_compaction(finished)?;
+                }
+                self.compact(all)
+            }
+            None => self.compact(all),
+        }
+    }
+
+    fn compact(&self, all: bool) -> Result<()> {
+        let pending = self.start_compaction(all)?;
+        let finished = self.copy_live_records(pending);
+        self.install_compaction(finished)
+    }
+
+    fn should_compact(&self) -> bool {
+        let (size, stale) = self
+            .segments
+            .lock()
+            .unwrap()
+            .values()
+            .fold((0, 0), |acc, s| (acc.0 + s.size, acc.1 + s.stale));
+        stale >= self.config.compaction_threshold
+            || (size >= self.config.compaction_min_size
+                && stale as f64 >= size as f64 * self.config.compaction_ratio)
+    }
+
+    /// Called by writers after each write.
+    /// Installs a finished background compaction, then starts a new one on a
+    /// background thread once the stale thresholds are crossed.
+    /// Files are only created, renamed and removed here, on the writer's
+    /// thread; the background thread just fills in already created files.
+    pub(crate) fn maybe_compact(&self) {
+        let background = match &self.background {
+            Some(background) => background,
+            None => return,
+        };
+        let mut running = match background.compaction.try_lock() {
+            Ok(running) => running,
+            Err(_) => return,
+        };
+        let logger = &self.config.logger;
+        if running.is_some() {
+            if !background.compaction_done.load(Ordering::SeqCst) {
+                return;
+            }
+            let handle = running.take().unwrap();
+            let finished = handle.join().expect("compaction thread panicked");
+            match self.install_compaction(finished) {
+                Ok(_) => info!(logger, "finish compaction"),
+                Err(e) => error!(logger, "fail compaction: {}", e),
+            }
+        }
+        if !self.should_compact() {
+            return;
+        }
+
+        let pending = match self.start_compaction(false) {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!(logger, "fail to start compaction: {}", e);
+                return;
+            }
+        };
+        info!(logger, "start compaction"; "inputs" => pending.inputs.len());
+        let store = KvStore {
+            background: None,
+            ..self.clone()
+        };
+        let done = Arc::clone(&background.compaction_done);
+        done.store(false, Ordering::SeqCst);
+        *running = Some(std::thread::spawn(move || {
+            let finished = store.copy_live_records(pending);
+            done.store(true, Ordering::SeqCst);
+            finished
+        }));
+    }
+
+    fn live_positions_in(&self, gens: &HashSet<u64>) -> Vec<(Vec<u8>, CommandPos)> {
+        let mut positions: Vec<(Vec<u8>, CommandPos)> = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, x)| gens.contains(&x.gen))
+            .map(|(key, x)| (key.clone(), *x))
+            .collect();
+        positions.sort_by_key(|(_, x)| (x.gen, x.pos));
+        positions
+    }
+
+    /// Pick the segments to compact from `segments`, all of which are immutable.
+    /// Only a prefix of the oldest segments is taken, because a tombstone can
+    /// be dropped only once no older segment can hold the value it removes.
+    /// The prefix ends at the newest segment that is stale enough on its own,
+    /// or covers everything when no single segment is.
+    fn compaction_inputs(&self, segments: &BTreeMap<u64, SegmentInfo>) -> Vec<u64> {
+        let ratio = self.config.compaction_ratio;
+        let last = segments
+            .iter()
+            .rev()
+            .find(|(_, s)| s.stale > 0 && s.stale as f64 >= s.size as f64 * ratio)
+            .map(|(&gen, _)| gen);
+        segments
+            .keys()
+            .cloned()
+            .filter(|&gen| last.is_none_or(|last| gen <= last))
+            .collect()
+    }
+
+    /// Pick the inputs, or take every segment with `all` set, and create the
+    /// output files.
+    /// The outputs are numbered right after the current segment and the
+    /// writer skips past them, so writers are only blocked for this step.
+    fn start_compaction(&self, all: bool) -> Result<PendingCompaction> {
+        let mut w = self.writer()?.lock().expect("compact: cant lock writer");
+        w.writer.flush()?;
+        // expired keys are left out of the copy
+        self.sweep_expired();
+        let segments = self.segments.lock().unwrap().clone();
+        let inputs = if all {
+            segments.keys().cloned().collect()
+        } else {
+            self.compaction_inputs(&segments)
+        };
+        let live = self.live_positions_in(&inputs.iter().cloned().collect());
+        // records sealed under another key may grow when they are rewritten
+        let growth = match self.config.encryption_key {
+            Some(_) => record::SEALED_OVERHEAD,
+            None => 0,
+        };
+        let live_size: u64 = live.iter().map(|(_, x)| x.len + growth).sum();
+        let reserved = live_size / self.config.segment_size + 1;
+        let outputs = w.gen + 1..w.gen + 1 + reserved;
+
+        let mut temps = Vec::new();
+        for gen in outputs.clone() {
+            temps.push((
+                create_temp(&temp_path(&self.path, gen))?,
+                create_temp(&hint_temp_path(&self.path, gen))?,
+            ));
+        }
+        self.switch_segment(&mut w, outputs.end)?;
+        self.segments
+            .lock()
+            .unwrap()
+            .insert(w.gen, SegmentInfo::default());
+
+        Ok(PendingCompaction {
+            inputs,
+            live,
+            outputs,
+            temps,
+        })
+    }
+
+    fn copy_live_records(&self, pending: PendingCompaction) -> FinishedCompaction {
+        FinishedCompaction {
+            result: self.write_compacted_segments(&pending),
+            inputs: pending.inputs,
+            outputs: pending.outputs,
+        }
+    }
+
+    /// Copy the live records into the output files, write their hints and
+    /// sync them.
+    /// Records not written the way new ones are, such as those sealed under
+    /// a previous key, are rewritten; the others are copied as they are.
+    /// Should the rewritten records outgrow the outputs, the last one takes
+    /// the rest.
+    /// Returns where each record moved to, keyed by its old location,
+    /// and the sizes of the written segments.
+    fn write_compacted_segments(
+        &self,
+        pending: &PendingCompaction,
+    ) -> Result<(Moved, BTreeMap<u64, SegmentInfo>)> {
+        let mut readers: HashMap<u64, File> = HashMap::new();
+        let mut moved = HashMap::new();
+        let mut written = BTreeMap::new();
+        let mut outputs = pending.outputs.clone().zip(pending.temps.iter()).peekable();
+        let mut current: Option<(u64, BufWriter<&File>, &File)> = None;
+        let mut hints = Vec::new();
+        let mut pos = 0;
+        let enc_key = self.config.encryption_key.as_ref();
+
+        for (key, cmd_pos) in pending.live.iter() {
+            if current.is_none() || (pos >= self.config.segment_size && outputs.peek().is_some()) {
+                if let Some((gen, writer, hint_file)) = current.take() {
+                    finish_segment(writer, hint_file, pos, &hints, enc_key)?;
+                    written.insert(
+                        gen,
+                        SegmentInfo {
+                            size: pos,
+                            stale: 0,
+                        },
+                    );
+                }
+                let (gen, (file, hint_file)) = outputs
+                    .next()
+                    .ok_or_else(|| KvsError::from(KvsErrorKind::Index))?;
+                current = Some((gen, BufWriter::new(file), hint_file));
+                hints.clear();
+                pos = 0;
+            }
+            let (gen, writer, _) = current.as_mut().unwrap();
+
+            let reader = match readers.entry(cmd_pos.gen) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(File::open(self.log_path(cmd_pos.gen))?),
+            };
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            let mut buf = vec![0; cmd_pos.len as usize];
+            reader.read_exact(&mut buf)?;
+            if !record::is_current(&buf, &self.config) {
+                buf = record::encode(&record::decode(&buf, &self.config)?, &self.config);
+            }
+            writer.write_all(&buf)?;
+            let len = buf.len() as u64;
+            let new_pos = CommandPos {
+                gen: *gen,
+                pos,
+                len,
+                ..*cmd_pos
+            };
+            moved.insert((cmd_pos.gen, cmd_pos.pos), new_pos);
+            hints.push((key.clone(), new_pos));
+            pos += len;
+        }
+        if let Some((gen, writer, hint_file)) = current {
+            finish_segment(writer, hint_file, pos, &hints, enc_key)?;
+            written.insert(
+                gen,
+                SegmentInfo {
+                    size: pos,
+                    stale: 0,
+                },
+            );
+        }
+
+        Ok((moved, written))
+    }
+
+    /// Rename the written outputs into place, point the index at them and
+    /// remove the inputs.
+    /// A crash before the renames leaves only temporary files, which the
+    /// next open removes; a crash after them replays the inputs first and
+    /// the outputs supersede them. A segment is renamed before its hint, so
+    /// a hint never exists without its segment.
+    fn install_compaction(&self, finished: FinishedCompaction) -> Result<()> {
+        let result = finished.result.and_then(|(moved, written)| {
+            for gen in written.keys() {
+                std::fs::rename(temp_path(&self.path, *gen), self.log_path(*gen))?;
+                std::fs::rename(hint_temp_path(&self.path, *gen), self.hint_path(*gen))?;
+            }
+            Ok((moved, written))
+        });
+        for gen in finished.outputs {
+            for temp in &[temp_path(&self.path, gen), hint_temp_path(&self.path, gen)] {
+                if temp.exists() {
+                    std::fs::remove_file(temp)?;
+                }
+            }
+        }
+        sync_dir(&self.path)?;
+        let (mut moved, mut written) = result?;
+
+        {
+            let mut index = self.index.write().unwrap();
+            for cmd_pos in index.values_mut() {
+                if let Some(new_pos) = moved.remove(&(cmd_pos.gen, cmd_pos.pos)) {
+                    *cmd_pos = new_pos;
+                }
+            }
+            // the rest were overwritten while they were being copied
+            for cmd_pos in moved.values() {
+                add_stale(&mut written, *cmd_pos);
+            }
+            let mut segments = self.segments.lock().unwrap();
+            for gen in finished.inputs.iter() {
+                segments.remove(gen);
+            }
+            segments.append(&mut written);
+            // inputs are always the oldest segments
+            if let Some(&first) = segments.keys().next() {
+                self.safe_point.store(first, Ordering::SeqCst);
+            }
+        }
+        *self.last_compaction.lock().unwrap() = Some(SystemTime::now());
+
+        // Nothing points into the inputs any more. Remove them oldest first,
+        // so a crash in between still replays correctly.
+        for gen in finished.inputs {
+            let hint = self.hint_path(gen);
+            if hint.exists() {
+                std::fs::remove_file(hint)?;
+            }
+            std::fs::remove_file(self.log_path(gen))?;
+        }
+        sync_dir(&self.path)?;
+        Ok(())
+    }
+}
+
+fn create_temp(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .map_err(Into::into)
+}
+
+fn finish_segment(
+    writer: BufWriter<&File>,
+    hint_file: &File,
+    size: u64,
+    hints: &[(Vec<u8>, CommandPos)],
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    hint::write(hint_file, size, hints, key)
+}
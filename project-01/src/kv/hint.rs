@@ -0,0 +1,118 @@
+//! Hint files list the keys of a compacted segment with their locations,
+//! so opening a store doesn't have to read the values
+//!
+//! ```text
+//! | version: u8 | log_size: u64 | entry ... | crc: u32 |
+//! entry: | key_len: u32 | pos: u64 | len: u64 | expires_at: u64 | key |
+//! ```
+//!
+//! Integers are little endian. The CRC32 covers everything before it.
+//! `expires_at` is 0 for keys that don't expire. Files of other versions
+//! are ignored, and their segment is read instead.
+//!
+//! The hints of an encrypted store are sealed by `cipher`, as a version 3
+//! byte followed by the sealed version 2 file. Once a key is set, hints in
+//! the clear are ignored, so they can't be forged.
+
+use super::cipher::{self, EncryptionKey};
+use super::{CommandPos, KvStoreConfig};
+use crate::Result;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Keys of a segment with their locations
+pub(crate) type Hints = Vec<(Vec<u8>, CommandPos)>;
+
+const VERSION: u8 = 2;
+const ENTRY_HEADER_LEN: usize = 28;
+const VERSION_SEALED: u8 = 3;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Write the hints of a segment of `log_size` bytes into `file`, sealed
+/// with `key` if there is one, and sync it
+pub(crate) fn write(
+    mut file: &File,
+    log_size: u64,
+    entries: &[(Vec<u8>, CommandPos)],
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
+    let mut buf = vec![VERSION];
+    buf.extend_from_slice(&log_size.to_le_bytes());
+    for (key, cmd_pos) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        buf.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    if let Some(key) = key {
+        let sealed = cipher::seal(key, &[VERSION_SEALED], &buf);
+        buf = [&[VERSION_SEALED][..], &sealed[..]].concat();
+    }
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Read the hints of segment `gen`, opening them with one of the keys of
+/// `config`. Returns `None` when the file is damaged, none of the keys
+/// opens it, or it is in the clear while `config` requires sealed hints.
+pub(crate) fn read(path: &Path, gen: u64, config: &KvStoreConfig) -> Result<Option<(u64, Hints)>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.first() == Some(&VERSION_SEALED) {
+        buf = match cipher::open(config.decryption_keys(), &[VERSION_SEALED], &buf[1..]) {
+            Ok(opened) => opened,
+            Err(_) => return Ok(None),
+        };
+    } else if config.requires_sealed() {
+        return Ok(None);
+    }
+    if buf.len() < 13 {
+        return Ok(None);
+    }
+    if buf[0] != VERSION {
+        return Ok(None);
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != read_u32(crc) {
+        return Ok(None);
+    }
+
+    let log_size = read_u64(&body[1..]);
+    let mut entries = Vec::new();
+    let mut rest = &body[9..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return Ok(None);
+        }
+        let key_end = ENTRY_HEADER_LEN + read_u32(rest) as usize;
+        if rest.len() < key_end {
+            return Ok(None);
+        }
+        let key = rest[ENTRY_HEADER_LEN..key_end].to_vec();
+        let cmd_pos = CommandPos {
+            gen,
+            pos: read_u64(&rest[4..]),
+            len: read_u64(&rest[12..]),
+            expires_at: Some(read_u64(&rest[20..])).filter(|&x| x != 0),
+        };
+        entries.push((key, cmd_pos));
+        rest = &rest[key_end..];
+    }
+    Ok(Some((log_size, entries)))
+}
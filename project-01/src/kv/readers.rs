@@ -0,0 +1,75 @@
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Idle read handles of segment files, shared by every clone of a store.
+/// A reader takes a handle out while it seeks and reads, so concurrent
+/// reads never share a file position, and puts it back when done.
+#[derive(Default)]
+pub(crate) struct ReaderPool {
+    idle: Mutex<HashMap<u64, Vec<File>>>,
+    /// Segment files opened so far
+    opened: AtomicU64,
+}
+
+/// Handle of a segment file taken out of a `ReaderPool`
+pub(crate) struct PooledReader<'a> {
+    pool: &'a ReaderPool,
+    gen: u64,
+    file: Option<File>,
+}
+
+impl ReaderPool {
+    /// Take an idle handle of segment `gen`, or open it at `path`.
+    /// Handles of segments older than `safe_point` are closed on the way.
+    pub(crate) fn get(&self, gen: u64, path: &Path, safe_point: u64) -> Result<PooledReader<'_>> {
+        let idle = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.retain(|&g, _| g >= safe_point);
+            idle.get_mut(&gen).and_then(Vec::pop)
+        };
+        let file = match idle {
+            Some(file) => file,
+            None => {
+                self.opened.fetch_add(1, Ordering::Relaxed);
+                File::open(path)?
+            }
+        };
+        Ok(PooledReader {
+            pool: self,
+            gen,
+            file: Some(file),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+}
+
+impl Deref for PooledReader<'_> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.file.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledReader<'_> {
+    fn deref_mut(&mut self) -> &mut File {
+        self.file.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledReader<'_> {
+    fn drop(&mut self) {
+        let file = self.file.take().unwrap();
+        let mut idle = self.pool.idle.lock().unwrap();
+        idle.entry(self.gen).or_default().push(file);
+    }
+}
@@ -0,0 +1,293 @@
+//! Binary layout of a log record
+//!
+//! ```text
+//! | version: u8 | kind: u8 | flags: u8 | crc: u32 | key_len: u32 | value_len: u32 | key | value |
+//! ```
+//!
+//! Integers are little endian. The CRC32 covers every byte of the record
+//! except the CRC itself.
+//!
+//! A batch record has no key; its value holds the records of the batch.
+//! A set record with the `FLAG_EXPIRES` flag starts its value with the
+//! expiry time as a `u64` of milliseconds since the Unix epoch.
+//!
+//! The `CODEC_MASK` bits of the flags name the codec of a set record's
+//! value, after the expiry time. Records inside a batch are never
+//! compressed, so their lengths follow from their commands.
+//!
+//! A record with the `FLAG_SEALED` flag is encrypted: its key is empty and
+//! its value is sealed by `cipher`, with the version, kind and flags as
+//! associated data. Opened, it reads `| key_len: u32 | key | value |`.
+//! The records inside a batch are sealed one by one, and either all of
+//! them are or none is; the batch record itself is never sealed.
+//! Once a key is set, records in the clear are rejected as forged.
+
+use super::cipher::{self, EncryptionKey};
+use super::{Command, Compression, KvStoreConfig};
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use std::borrow::Cow;
+use std::io::prelude::*;
+
+pub(crate) const VERSION: u8 = 1;
+pub(crate) const HEADER_LEN: u64 = 15;
+
+const KIND_SET: u8 = 1;
+const KIND_RM: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const FLAG_EXPIRES: u8 = 1;
+const CODEC_MASK: u8 = 0b110;
+const CODEC_LZ4: u8 = 0b010;
+const FLAG_SEALED: u8 = 0b1000;
+const EXPIRY_LEN: usize = 8;
+const CRC_RANGE: std::ops::Range<usize> = 3..7;
+/// Bytes sealing adds to a record
+pub(crate) const SEALED_OVERHEAD: u64 = 4 + cipher::OVERHEAD as u64;
+
+fn corruption() -> KvsError {
+    KvsError::from(KvsErrorKind::Corruption)
+}
+
+fn checksum(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..CRC_RANGE.start]);
+    hasher.update(&record[CRC_RANGE.end..]);
+    hasher.finalize()
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Value compressed with `compression` if it is at least `threshold` bytes
+/// and shrinks, with the codec bits for the flags
+fn compress(value: &[u8], compression: Compression, threshold: usize) -> Option<(u8, Vec<u8>)> {
+    let compressed = match compression {
+        Compression::None => return None,
+        _ if value.len() < threshold => return None,
+        Compression::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(value)),
+    };
+    Some(compressed).filter(|(_, bytes)| bytes.len() < value.len())
+}
+
+fn decompress(codec: u8, value: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        0 => Ok(value.to_vec()),
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(value).map_err(|_| corruption()),
+        _ => Err(corruption()),
+    }
+}
+
+/// Serialize `command` into one record with the codec and key of `config`
+pub(crate) fn encode(command: &Command, config: &KvStoreConfig) -> Vec<u8> {
+    encode_with(
+        command,
+        config.compression,
+        config.compression_threshold,
+        config.encryption_key.as_ref(),
+    )
+}
+
+/// Serialize `command` into one record, compressing the value of a set
+/// with `compression` if it is at least `threshold` bytes and sealing it
+/// with `key`
+pub(crate) fn encode_with(
+    command: &Command,
+    compression: Compression,
+    threshold: usize,
+    key: Option<&EncryptionKey>,
+) -> Vec<u8> {
+    let owned: Vec<u8>;
+    let (kind, mut flags, mut body) = match command {
+        Command::Set((key, value)) => match compress(value, compression, threshold) {
+            Some((codec, compressed)) => {
+                owned = compressed;
+                (KIND_SET, codec, (&key[..], &owned[..]))
+            }
+            None => (KIND_SET, 0, (&key[..], &value[..])),
+        },
+        Command::SetExpiring((key, value, expires_at)) => {
+            let (codec, value) = match compress(value, compression, threshold) {
+                Some((codec, compressed)) => (codec, Cow::Owned(compressed)),
+                None => (0, Cow::Borrowed(&value[..])),
+            };
+            owned = [&expires_at.to_le_bytes()[..], &value[..]].concat();
+            (KIND_SET, FLAG_EXPIRES | codec, (&key[..], &owned[..]))
+        }
+        Command::Rm(key) => (KIND_RM, 0, (&key[..], &[][..])),
+        Command::Batch(commands) => {
+            owned = commands
+                .iter()
+                .flat_map(|command| encode_with(command, Compression::None, 0, key))
+                .collect();
+            (KIND_BATCH, 0, (&[][..], &owned[..]))
+        }
+    };
+    let sealed: Vec<u8>;
+    if let Some(enc_key) = key.filter(|_| kind != KIND_BATCH) {
+        flags |= FLAG_SEALED;
+        let (key, value) = body;
+        let inner = [&(key.len() as u32).to_le_bytes()[..], key, value].concat();
+        sealed = cipher::seal(enc_key, &[VERSION, kind, flags], &inner);
+        body = (&[][..], &sealed[..]);
+    }
+    let (key, value) = body;
+    let mut record = Vec::with_capacity(HEADER_LEN as usize + key.len() + value.len());
+    record.extend_from_slice(&[VERSION, kind, flags]);
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let crc = checksum(&record);
+    record[CRC_RANGE].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// Length of the record `encode` makes of `command` without compression
+/// or sealing
+pub(crate) fn encoded_len(command: &Command) -> u64 {
+    HEADER_LEN
+        + match command {
+            Command::Set((key, value)) => (key.len() + value.len()) as u64,
+            Command::SetExpiring((key, value, _)) => (key.len() + EXPIRY_LEN + value.len()) as u64,
+            Command::Rm(key) => key.len() as u64,
+            Command::Batch(commands) => commands.iter().map(encoded_len).sum(),
+        }
+}
+
+/// Total record length announced by `header`
+pub(crate) fn record_len(header: &[u8]) -> Result<u64> {
+    if header[0] != VERSION {
+        return Err(corruption());
+    }
+    Ok(HEADER_LEN + read_u32(&header[7..]) as u64 + read_u32(&header[11..]) as u64)
+}
+
+/// Whether `header` starts a batch record
+pub(crate) fn is_batch(header: &[u8]) -> bool {
+    header.len() >= HEADER_LEN as usize && header[0] == VERSION && header[1] == KIND_BATCH
+}
+
+/// Whether the CRC of the whole record `record` matches its bytes
+pub(crate) fn checksum_matches(record: &[u8]) -> bool {
+    read_u32(&record[CRC_RANGE]) == checksum(record)
+}
+
+/// Whether `record` is written the way `config` writes new records:
+/// sealed with the current key, or in the clear when there is none
+pub(crate) fn is_current(record: &[u8], config: &KvStoreConfig) -> bool {
+    let sealed = &record[HEADER_LEN as usize..];
+    match &config.encryption_key {
+        Some(key) => record[2] & FLAG_SEALED != 0 && cipher::is_sealed_with(key, sealed),
+        None => record[2] & FLAG_SEALED == 0,
+    }
+}
+
+/// Check and deserialize one whole record, opening it with the keys of
+/// `config` if it is sealed
+pub(crate) fn decode(record: &[u8], config: &KvStoreConfig) -> Result<Command> {
+    if (record.len() as u64) < HEADER_LEN || record_len(record)? != record.len() as u64 {
+        return Err(corruption());
+    }
+    if !checksum_matches(record) {
+        return Err(corruption());
+    }
+    let key_end = HEADER_LEN as usize + read_u32(&record[7..]) as usize;
+    let opened: Vec<u8>;
+    let (key, body) = if record[2] & FLAG_SEALED != 0 {
+        if key_end != HEADER_LEN as usize {
+            return Err(corruption());
+        }
+        opened = cipher::open(config.decryption_keys(), &record[..3], &record[key_end..])?;
+        if opened.len() < 4 || opened.len() - 4 < read_u32(&opened) as usize {
+            return Err(corruption());
+        }
+        let key_end = 4 + read_u32(&opened) as usize;
+        (opened[4..key_end].to_vec(), &opened[key_end..])
+    } else if record[1] != KIND_BATCH && config.requires_sealed() {
+        return Err(KvsError::from(KvsErrorKind::Tampered));
+    } else {
+        (
+            record[HEADER_LEN as usize..key_end].to_vec(),
+            &record[key_end..],
+        )
+    };
+    let codec = record[2] & CODEC_MASK;
+    match (record[1], record[2] & !CODEC_MASK & !FLAG_SEALED, codec) {
+        (KIND_SET, 0, _) => Ok(Command::Set((key, decompress(codec, body)?))),
+        (KIND_SET, FLAG_EXPIRES, _) if body.len() >= EXPIRY_LEN => {
+            let expires_at = read_u64(body);
+            let value = decompress(codec, &body[EXPIRY_LEN..])?;
+            Ok(Command::SetExpiring((key, value, expires_at)))
+        }
+        (KIND_RM, 0, 0) => Ok(Command::Rm(key)),
+        (KIND_BATCH, 0, 0) if key.is_empty() && record[2] & FLAG_SEALED == 0 => {
+            let mut commands = Vec::new();
+            let mut rest = body;
+            let sealed = rest.get(2).map(|flags| flags & FLAG_SEALED);
+            while !rest.is_empty() {
+                if (rest.len() as u64) < HEADER_LEN
+                    || rest[1] == KIND_BATCH
+                    || rest[2] & CODEC_MASK != 0
+                    || Some(rest[2] & FLAG_SEALED) != sealed
+                {
+                    return Err(corruption());
+                }
+                let len = record_len(rest)? as usize;
+                if len > rest.len() {
+                    return Err(corruption());
+                }
+                commands.push(decode(&rest[..len], config)?);
+                rest = &rest[len..];
+            }
+            Ok(Command::Batch(commands))
+        }
+        _ => Err(corruption()),
+    }
+}
+
+/// Read the next record from `reader`, which has `remaining` bytes left.
+/// Returns `None` at the end of the file and the command with its length otherwise.
+pub(crate) fn read_next(
+    reader: &mut impl Read,
+    remaining: u64,
+    config: &KvStoreConfig,
+) -> Result<Option<(Command, u64)>> {
+    if remaining == 0 {
+        return Ok(None);
+    }
+    if remaining < HEADER_LEN {
+        return Err(corruption());
+    }
+    let mut record = vec![0; HEADER_LEN as usize];
+    reader.read_exact(&mut record)?;
+    let len = record_len(&record)?;
+    if len > remaining {
+        return Err(corruption());
+    }
+    record.resize(len as usize, 0);
+    reader.read_exact(&mut record[HEADER_LEN as usize..])?;
+    Ok(Some((decode(&record, config)?, len)))
+}
+
+/// Whether a bad record at the start of `rest`, which runs to the end of
+/// the file, is an interrupted append rather than damage to written data:
+/// the record reaches the end of the file, or nothing but zeros follows.
+pub(crate) fn is_torn_tail(rest: &[u8]) -> bool {
+    if (rest.len() as u64) < HEADER_LEN || rest.iter().all(|&b| b == 0) {
+        return true;
+    }
+    match record_len(rest) {
+        Ok(len) => len >= rest.len() as u64,
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,283 @@
+use super::record::{self, HEADER_LEN};
+use super::{
+    log_path, sorted_gen_list, Command, JsonCommand, KvStore, KvStoreConfig, LEGACY_FILE_NAME,
+    LOG_EXT,
+};
+use crate::engine::EngineMarker;
+use crate::error::{KvsError, KvsErrorKind};
+use crate::lock::DirLock;
+use crate::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What is wrong with a damaged range of the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DamageKind {
+    /// The file ends inside a record, as after an interrupted append
+    Truncated,
+    /// Bytes that don't parse as a record
+    Malformed,
+    /// A record whose CRC doesn't match its bytes
+    ChecksumMismatch,
+    /// A sealed record that none of the keys opens
+    Undecryptable,
+}
+
+/// Damaged range of a log file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Damage {
+    /// Log file holding the range
+    pub file: PathBuf,
+    /// Byte offset of the range in the file
+    pub offset: u64,
+    /// Bytes up to the next readable record or the end of the file
+    pub len: u64,
+    /// What is wrong with the first record of the range
+    pub kind: DamageKind,
+}
+
+/// Outcome of `KvStore::verify` and `KvStore::repair`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Log files checked, oldest first
+    pub files: Vec<PathBuf>,
+    /// Records that read back intact
+    pub records: u64,
+    /// Damaged ranges, in log order
+    pub damage: Vec<Damage>,
+}
+
+impl VerifyReport {
+    /// Whether the whole log reads back intact
+    pub fn is_clean(&self) -> bool {
+        self.damage.is_empty()
+    }
+
+    /// Bytes in damaged ranges
+    pub fn damaged_bytes(&self) -> u64 {
+        self.damage.iter().map(|x| x.len).sum()
+    }
+}
+
+impl fmt::Display for DamageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DamageKind::Truncated => "truncated",
+            DamageKind::Malformed => "malformed",
+            DamageKind::ChecksumMismatch => "checksum mismatch",
+            DamageKind::Undecryptable => "undecryptable",
+        })
+    }
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes at offset {}: {}",
+            self.file.display(),
+            self.len,
+            self.offset,
+            self.kind
+        )
+    }
+}
+
+/// One line per damaged range, then a summary line
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for damage in &self.damage {
+            writeln!(f, "{}", damage)?;
+        }
+        writeln!(
+            f,
+            "{} files, {} intact records, {} damaged bytes in {} ranges",
+            self.files.len(),
+            self.records,
+            self.damaged_bytes(),
+            self.damage.len()
+        )
+    }
+}
+
+impl KvStore {
+    /// Read every record of the store in `path` and report the damaged
+    /// ranges by offset. No record is changed.
+    /// Fails with `Locked` while a store has `path` open.
+    pub fn verify(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<VerifyReport> {
+        let path = path.into();
+        let _lock = lock(&path)?;
+        scan(&path, &config, |_| Ok(()))
+    }
+
+    /// Salvage every record of the store in `path` that still reads into a
+    /// new compacted store in `dest`, a directory that must not exist yet.
+    /// Both are opened with `config`. The returned report tells what was
+    /// lost; keys whose last write was lost read as they were before it.
+    /// Fails with `Locked` while a store has `path` open.
+    pub fn repair(
+        path: impl Into<PathBuf>,
+        dest: impl Into<PathBuf>,
+        config: KvStoreConfig,
+    ) -> Result<VerifyReport> {
+        let (path, dest) = (path.into(), dest.into());
+        let _lock = lock(&path)?;
+        std::fs::create_dir(&dest)?;
+        let mut store = KvStore::open_with_config(&dest, config.clone())?;
+        let report = scan(&path, &config, |command| store.commit(command, None))?;
+        // compacts every segment, leaving only the live records
+        store.rekey()?;
+        drop(store);
+        EngineMarker::claim(&dest, "kvs")?.set_encrypted(&dest, config.encryption_key.is_some())?;
+        Ok(report)
+    }
+}
+
+/// Lock the store in `dir` against being opened while it is read
+fn lock(dir: &Path) -> Result<DirLock> {
+    if !dir.is_dir() {
+        return Err(KvsError::from(KvsErrorKind::IO));
+    }
+    DirLock::acquire(dir)
+}
+
+/// Log files in `dir`, oldest first, as `open` would read them
+fn log_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let gens = sorted_gen_list(dir, LOG_EXT)?;
+    let legacy = dir.join(LEGACY_FILE_NAME);
+    if gens.is_empty() && legacy.is_file() {
+        return Ok(vec![legacy]);
+    }
+    Ok(gens.into_iter().map(|gen| log_path(dir, gen)).collect())
+}
+
+/// Read the log in the locked `dir`, passing each intact command to
+/// `salvage` in log order
+fn scan(
+    dir: &Path,
+    config: &KvStoreConfig,
+    mut salvage: impl FnMut(Command) -> Result<()>,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for file in log_files(dir)? {
+        let buf = std::fs::read(&file)?;
+        // segments written before the binary format hold JSON lines
+        let json = buf.first() == Some(&b'{');
+        let read = |rest: &[u8]| {
+            if json {
+                read_json_line(rest)
+            } else {
+                read_record(rest, config)
+            }
+        };
+        let mut pos = 0;
+        while pos < buf.len() {
+            let kind = match read(&buf[pos..]) {
+                Ok((command, len)) => {
+                    report.records += 1;
+                    salvage(command)?;
+                    pos += len;
+                    continue;
+                }
+                Err(kind) => kind,
+            };
+            // carry on at the next line, or the next offset holding an intact record
+            let next = if json {
+                pos + line_len(&buf[pos..])
+            } else {
+                pos + resume_after(&buf[pos..], config)
+            };
+            let kind = match kind {
+                DamageKind::Truncated if next < buf.len() => DamageKind::Malformed,
+                DamageKind::Malformed | DamageKind::ChecksumMismatch
+                    if !json && next == buf.len() && record::is_torn_tail(&buf[pos..]) =>
+                {
+                    DamageKind::Truncated
+                }
+                kind => kind,
+            };
+            report.damage.push(Damage {
+                file: file.clone(),
+                offset: pos as u64,
+                len: (next - pos) as u64,
+                kind,
+            });
+            pos = next;
+        }
+        report.files.push(file);
+    }
+    Ok(report)
+}
+
+/// Offset of the first intact record in `buf` at or after `from`
+fn next_record(buf: &[u8], from: usize, config: &KvStoreConfig) -> Option<usize> {
+    (from..buf.len()).find(|&next| read_record(&buf[next..], config).is_ok())
+}
+
+/// Offset in `rest` to carry on at after the bad record at its start.
+/// A batch is passed over whole, so none of its records is salvaged on its
+/// own; when the record after its length isn't intact either, the length
+/// can't be trusted and the rest of the file is dropped.
+fn resume_after(rest: &[u8], config: &KvStoreConfig) -> usize {
+    if !record::is_batch(rest) {
+        return next_record(rest, 1, config).unwrap_or(rest.len());
+    }
+    match record::record_len(rest).map(|len| len as usize) {
+        Ok(len) if len < rest.len() && read_record(&rest[len..], config).is_ok() => len,
+        _ => rest.len(),
+    }
+}
+
+/// Whether an intact record follows the bad record at the start of `rest`,
+/// which then can't be a torn append. The records inside a torn batch read
+/// whole and are passed over.
+pub(super) fn is_followed_by_record(rest: &[u8], config: &KvStoreConfig) -> bool {
+    let mut from = 1;
+    if record::is_batch(rest) {
+        from = HEADER_LEN as usize;
+        while let Ok((_, len)) = read_record(&rest[from..], config) {
+            from += len;
+        }
+    }
+    next_record(rest, from, config).is_some()
+}
+
+/// The record at the start of `buf` with its length, or what is wrong with it
+fn read_record(
+    buf: &[u8],
+    config: &KvStoreConfig,
+) -> std::result::Result<(Command, usize), DamageKind> {
+    if buf.len() < HEADER_LEN as usize {
+        return Err(DamageKind::Truncated);
+    }
+    let len = record::record_len(buf).map_err(|_| DamageKind::Malformed)? as usize;
+    if len > buf.len() {
+        return Err(DamageKind::Truncated);
+    }
+    if !record::checksum_matches(&buf[..len]) {
+        return Err(DamageKind::ChecksumMismatch);
+    }
+    match record::decode(&buf[..len], config) {
+        Ok(command) => Ok((command, len)),
+        Err(e) if e.is_missing_key() || e.is_tampered() => Err(DamageKind::Undecryptable),
+        Err(_) => Err(DamageKind::Malformed),
+    }
+}
+
+/// The JSON command on the line at the start of `buf` with the length of
+/// the line, or what is wrong with it.
+/// A line that doesn't end in a newline was cut off.
+fn read_json_line(buf: &[u8]) -> std::result::Result<(Command, usize), DamageKind> {
+    let len = line_len(buf);
+    match serde_json::from_slice::<JsonCommand>(&buf[..len]) {
+        Ok(command) => Ok((command.into(), len)),
+        Err(_) if buf[len - 1] != b'\n' => Err(DamageKind::Truncated),
+        Err(_) => Err(DamageKind::Malformed),
+    }
+}
+
+fn line_len(buf: &[u8]) -> usize {
+    buf.iter()
+        .position(|&b| b == b'\n')
+        .map_or(buf.len(), |end| end + 1)
+}
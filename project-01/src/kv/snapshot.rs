@@ -0,0 +1,70 @@
+use super::{value_of, CommandPos, KvStore, KvStoreConfig};
+use crate::engine::{is_empty_range, now_millis, KvsSnapshot, Pair};
+use crate::Result;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// Read-only view of a `KvStore` at the moment it was taken
+///
+/// It keeps a copy of the index and the segment files it points into open,
+/// so compaction deleting those files doesn't affect it.
+pub struct KvStoreSnapshot {
+    index: BTreeMap<Vec<u8>, CommandPos>,
+    files: RefCell<HashMap<u64, File>>,
+    config: Arc<KvStoreConfig>,
+}
+
+impl KvStore {
+    pub(crate) fn take_snapshot(&self) -> Result<KvStoreSnapshot> {
+        // hold the index, so compaction can't delete a segment before it is open
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        let index: BTreeMap<_, _> = index
+            .iter()
+            .filter(|(_, x)| !x.is_expired(now))
+            .map(|(key, &cmd_pos)| (key.clone(), cmd_pos))
+            .collect();
+        let gens: BTreeSet<u64> = index.values().map(|x| x.gen).collect();
+        let files = gens
+            .into_iter()
+            .map(|gen| Ok((gen, File::open(self.log_path(gen))?)))
+            .collect::<Result<_>>()?;
+        Ok(KvStoreSnapshot {
+            index,
+            files: RefCell::new(files),
+            config: Arc::clone(&self.config),
+        })
+    }
+}
+
+impl KvStoreSnapshot {
+    fn read(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        let mut files = self.files.borrow_mut();
+        let file = files.get_mut(&cmd_pos.gen).unwrap();
+        value_of(key, KvStore::read_command(file, cmd_pos, &self.config)?)
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        match self.index.get(&key) {
+            Some(&cmd_pos) => self.read(&key, cmd_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Result<Vec<Pair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        self.index
+            .range(range)
+            .take(limit)
+            .map(|(key, &cmd_pos)| Ok((key.clone(), self.read(key, cmd_pos)?)))
+            .collect()
+    }
+}
@@ -8,17 +8,23 @@
 //!     assert_eq!(store.get("key".to_owned()), Some("value".to_owned()));
 //! }
 //!
+pub use batch::WriteBatch;
 pub use client::KvsClient;
-pub use engine::KvsEngine;
-pub use engine::SledKvsEngine;
-pub use kv::{KvStore, Result};
+pub use engine::{EngineMarker, EngineStats, KvsEngine, KvsSnapshot, Pair};
+pub use engine::{SledKvsEngine, SledSnapshot};
+pub use kv::{
+    Compression, Damage, DamageKind, Durability, EncryptionKey, KvStore, KvStoreConfig,
+    KvStoreSnapshot, Result, VerifyReport,
+};
 pub use server::KvsServer;
 
+mod batch;
 mod client;
 mod command;
 mod engine;
 mod error;
 mod kv;
+mod lock;
 mod server;
 
 /// thead_pool
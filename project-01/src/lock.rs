@@ -0,0 +1,80 @@
+//! Advisory lock that keeps a data directory to one process
+
+use crate::error::{KvsError, KvsErrorKind};
+use crate::Result;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOCK_FILE_NAME: &str = "kvs.lock";
+
+/// Exclusive lock on the lock file of a data directory, released when dropped.
+/// Engines share one among their clones.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock `dir`, failing with `Locked` if it is already locked, even by
+    /// this process
+    pub(crate) fn acquire(dir: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE_NAME))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(KvsError::from(KvsErrorKind::Locked))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Share the lock of `dir` with other readers, failing with `Locked`
+    /// while a store holds it. Creates no file: a directory without a lock
+    /// file has no store using it, and gives `None`.
+    pub(crate) fn acquire_shared(dir: &Path) -> Result<Option<Self>> {
+        let file = match File::open(dir.join(LOCK_FILE_NAME)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // std has a method of the same name, with another error type
+        match FileExt::try_lock_shared(&file) {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(KvsError::from(KvsErrorKind::Locked))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Wait up to `timeout` for no process to hold a lock on `path`, failing
+/// with `Locked` if one still does. A missing file isn't locked.
+pub(crate) fn wait_until_unlocked(path: &Path, timeout: Duration) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let started = Instant::now();
+    loop {
+        // closing the file releases the lock taken to test it
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                if started.elapsed() >= timeout {
+                    return Err(KvsError::from(KvsErrorKind::Locked));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
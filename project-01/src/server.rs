@@ -1,20 +1,26 @@
 use crate::command::{Request, Response};
-use crate::engine::KvsEngine;
-use crate::error::KvsErrorKind;
+use crate::engine::{into_string, prefix_range, KvsEngine};
+use crate::error::{KvsError, KvsErrorKind};
 use crate::thread_pool::ThreadPool;
 use crate::Result;
 use slog::*;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufReader, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::str::FromStr;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
+/// Most pairs a SCAN returns in one page; larger limits are cut to it
+const MAX_SCAN_LIMIT: usize = 10_000;
+
 /// Key Value Store server
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     engine: E,
     thread_pool: T,
+    /// Directory BACKUP requests write into; `None` refuses them
+    backup_dir: Option<Arc<PathBuf>>,
 }
 
 impl<E: KvsEngine, T: 'static + ThreadPool> KvsServer<E, T>
@@ -27,22 +33,35 @@ where
         Self {
             engine,
             thread_pool,
+            backup_dir: None,
         }
     }
 
+    /// Accept BACKUP requests, writing each into a new directory inside `dir`
+    pub fn with_backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
     /// Execute KvsServer
     pub fn run<A: ToSocketAddrs>(self, addr: A, logger: Logger) -> Result<Shutdown> {
         let stop = Arc::new(AtomicBool::new(false));
         let should_shutdown = Arc::clone(&stop);
         let listener = TcpListener::bind(addr)?;
         let thread = std::thread::spawn(move || {
-            for stream in listener.incoming().take_while(|_| !stop.load(Ordering::Relaxed)) {
+            for stream in listener
+                .incoming()
+                .take_while(|_| !stop.load(Ordering::Relaxed))
+            {
                 match stream {
                     Ok(xs) => {
                         debug!(logger, "accept connection from {}", xs.peer_addr().unwrap());
                         let e = self.engine.clone();
+                        let b = self.backup_dir.clone();
                         let l = logger.clone();
-                        self.thread_pool.spawn(move || handle_stream(e, xs, &l));
+                        self.thread_pool.spawn(move || {
+                            handle_stream(e, b.as_ref().map(|b| b.as_path()), xs, &l)
+                        });
                     }
                     Err(e) => {
                         warn!(logger, "{}", e);
@@ -52,9 +71,11 @@ where
             info!(logger, "stopping worker thread");
             self.thread_pool.shutdown();
             info!(logger, "stop receive thread");
-
         });
-        Ok(Shutdown { should_shutdown, thread })
+        Ok(Shutdown {
+            should_shutdown,
+            thread,
+        })
     }
 }
 
@@ -70,24 +91,21 @@ impl Shutdown {
     }
 }
 
-fn handle_stream<E: KvsEngine>(engine: E, mut xs: TcpStream, logger: &Logger) {
-    let mut buff = String::new();
+fn handle_stream<E: KvsEngine>(
+    engine: E,
+    backup_dir: Option<&Path>,
+    mut xs: TcpStream,
+    logger: &Logger,
+) {
     let mut reader = BufReader::new(xs.try_clone().unwrap());
-    let h = reader
-        .read_line(&mut buff)
-        .map_err(Into::into)
-        .and_then(|_| {
-            debug!(logger, "accept message {:?}", buff);
-            Request::from_str(buff.as_str())
-        })
+    let h = Request::read(&mut reader)
         .map(|request| {
             debug!(logger, "parsed request {:?}", request);
-            process(engine, request)
+            process(engine, backup_dir, request)
         })
         .and_then(|response| {
-            let s = response.to_string();
-            debug!(logger, "response {:?}", s);
-            xs.write_all(s.as_bytes()).map_err(Into::into)
+            debug!(logger, "response {:?}", response);
+            xs.write_all(&response.encode()).map_err(Into::into)
         })
         .and_then(|_| xs.flush().map_err(Into::into));
     match h {
@@ -100,9 +118,9 @@ fn handle_stream<E: KvsEngine>(engine: E, mut xs: TcpStream, logger: &Logger) {
     }
 }
 
-fn process<E: KvsEngine>(engine: E, request: Request) -> Response {
+fn process<E: KvsEngine>(engine: E, backup_dir: Option<&Path>, request: Request) -> Response {
     match request {
-        Request::Get { key } => engine.get(key).map_or_else(
+        Request::Get { key } => engine.get_bytes(key).map_or_else(
             |x| Response::Error {
                 message: x.to_string(),
             },
@@ -111,7 +129,7 @@ fn process<E: KvsEngine>(engine: E, request: Request) -> Response {
                     || Response::Error {
                         message: KvsErrorKind::KeyNotFound.to_string(),
                     },
-                    |value| Response::String { value },
+                    |value| Response::Value { value },
                 )
             },
         ),
@@ -119,17 +137,155 @@ fn process<E: KvsEngine>(engine: E, request: Request) -> Response {
             |x| Response::Error {
                 message: x.to_string(),
             },
-            |_| Response::String {
-                value: "".to_string(),
-            },
+            |_| Response::Ok,
         ),
         Request::Remove { key } => engine.remove(key).map_or_else(
             |x| Response::Error {
                 message: x.to_string(),
             },
-            |_| Response::String {
-                value: "".to_string(),
+            |_| Response::Ok,
+        ),
+        Request::SetIfAbsent { key, value } => empty_response(engine.set_if_absent(key, value)),
+        Request::SetIfPresent { key, value } => empty_response(engine.set_if_present(key, value)),
+        Request::CompareAndSwap {
+            key,
+            expected,
+            value,
+        } => empty_response(engine.compare_and_swap(key, expected, value)),
+        Request::SetWithTtl { key, value, ttl } => {
+            empty_response(engine.set_with_ttl(key, value, ttl))
+        }
+        Request::Expire { key, ttl } => empty_response(engine.expire(key, ttl)),
+        Request::Scan {
+            prefix,
+            cursor,
+            limit,
+        } => {
+            let limit = limit.min(MAX_SCAN_LIMIT);
+            let (start, end) = prefix_range(prefix);
+            // a cursor before the prefix mustn't widen the range
+            let start = match (start, cursor) {
+                (Bound::Included(prefix), Some(cursor)) if cursor < prefix => {
+                    Bound::Included(prefix)
+                }
+                (start, cursor) => cursor.map_or(start, Bound::Excluded),
+            };
+            engine.scan_bytes((start, end), limit).map_or_else(
+                |x| Response::Error {
+                    message: x.to_string(),
+                },
+                |pairs| {
+                    // a full page may have more after it
+                    let cursor = match pairs.last() {
+                        Some((key, _)) if pairs.len() == limit => Some(key.clone()),
+                        _ => None,
+                    };
+                    Response::Pairs { pairs, cursor }
+                },
+            )
+        }
+        Request::Backup { dest } => {
+            empty_response(backup_dest(backup_dir, dest).and_then(|dest| engine.checkpoint(dest)))
+        }
+        Request::Info => engine.stats().map_or_else(
+            |x| Response::Error {
+                message: x.to_string(),
+            },
+            |stats| Response::Value {
+                value: stats.to_string().into_bytes(),
             },
         ),
     }
 }
+
+/// Where to write the backup a client named `dest`: a relative path inside
+/// `backup_dir`, which can't climb out of it
+fn backup_dest(backup_dir: Option<&Path>, dest: Vec<u8>) -> Result<PathBuf> {
+    let forbidden = || KvsError::from(KvsErrorKind::Forbidden);
+    let backup_dir = backup_dir.ok_or_else(forbidden)?;
+    let dest = PathBuf::from(into_string(dest)?);
+    let plain = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !plain || dest.as_os_str().is_empty() {
+        return Err(forbidden());
+    }
+    Ok(backup_dir.join(dest))
+}
+
+fn empty_response(result: Result<()>) -> Response {
+    result.map_or_else(
+        |x| Response::Error {
+            message: x.to_string(),
+        },
+        |_| Response::Ok,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, KvsServer, MAX_SCAN_LIMIT};
+    use crate::command::{Request, Response};
+    use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use crate::{KvStore, KvsClient, KvsEngine};
+    use slog::{o, Logger};
+    use tempfile::TempDir;
+
+    // Connections should share open segment files, not open their own
+    #[test]
+    fn connections_share_open_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        let logger = Logger::root(slog::Discard, o!());
+        let addr = "127.0.0.1:4019";
+        let shutdown = KvsServer::new(store.clone(), pool)
+            .run(addr, logger)
+            .unwrap();
+
+        for _ in 0..100 {
+            let mut client = KvsClient::connect(addr).unwrap();
+            assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+        }
+        // at most one file per worker, reading at the same time
+        assert!(store.readers.opened() <= 4);
+        shutdown.do_shutdown().unwrap();
+    }
+
+    // A cursor should only move a scan forward inside its prefix, and a
+    // page should never exceed the cap
+    #[test]
+    fn scan_stays_inside_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key in &["a", "b", "key1", "key2", "kez"] {
+            store.set(*key, "value").unwrap();
+        }
+        let keys = |prefix: &str, cursor: Option<&str>, limit: usize| {
+            let request = Request::Scan {
+                prefix: prefix.into(),
+                cursor: cursor.map(Into::into),
+                limit,
+            };
+            match process(store.clone(), None, request) {
+                Response::Pairs { pairs, .. } => pairs
+                    .into_iter()
+                    .map(|(key, _)| String::from_utf8(key).unwrap())
+                    .collect::<Vec<_>>(),
+                _ => panic!("scan failed"),
+            }
+        };
+        assert_eq!(keys("key", Some("a"), 10), vec!["key1", "key2"]);
+        assert_eq!(keys("key", Some("key1"), 10), vec!["key2"]);
+        assert_eq!(keys("key", Some("kez"), 10).len(), 0);
+        assert_eq!(keys("", Some("b"), 10), vec!["key1", "key2", "kez"]);
+
+        let mut batch = crate::WriteBatch::new();
+        for i in 0..MAX_SCAN_LIMIT + 1 {
+            batch.set(format!("many{:05}", i), "value");
+        }
+        store.apply_batch(batch).unwrap();
+        assert_eq!(keys("many", None, usize::MAX).len(), MAX_SCAN_LIMIT);
+    }
+}
@@ -1,10 +1,11 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use predicates::str::{contains, is_empty, starts_with};
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -171,6 +172,33 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--durability", "sometimes", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--durability", "periodic", "--sync-interval", "10"])
+        .args(&["--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Periodic(10ms)"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -212,6 +240,545 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[
+        ("key3", "c"),
+        ("key1", "a"),
+        ("other", "x"),
+        ("key2", "b b"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key", "--page-size", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 a\nkey2 b b\nkey3 c\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 a\nkey2 b b\nkey3 c\nother x\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_spaces_and_newlines() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "a key", "line one\r\nline two", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "a key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("line one\r\nline two\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_encryption_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_dir = TempDir::new().unwrap();
+    let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+    let key_file = key_dir.path().join("kvs.key");
+    fs::write(&key_file, format!("{}\n", key)).unwrap();
+    let addr = "127.0.0.1:4009";
+
+    // a store written in the clear is sealed when the key is first given
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--durability", "always"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "plain", "value0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            addr,
+            "--durability",
+            "always",
+            "--key-env",
+            "KVS_KEY",
+        ])
+        .env("KVS_KEY", key)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "token", "secret", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    for entry in fs::read_dir(&temp_dir).unwrap() {
+        let data = fs::read(entry.unwrap().path()).unwrap();
+        assert!(!data.windows(6).any(|x| x == b"secret"));
+        assert!(!data.windows(6).any(|x| x == b"value0"));
+    }
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--key-file"])
+        .arg(&key_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "token", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("secret\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "plain", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value0\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", addr, "--key-file"])
+        .arg(&key_file)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_locked_directory() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4010"), ("sled", "127.0.0.1:4011")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", "127.0.0.1:4012"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Locked"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_read_only() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4013"), ("sled", "127.0.0.1:4014")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr, "--read-only"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key2", "value2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("ReadOnly"));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_info() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4015"), ("sled", "127.0.0.1:4016")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        for key in &["key1", "key2"] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", key, "value", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success();
+        }
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["info", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(starts_with("keys:2\nlog_bytes:"));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_backup() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4017"), ("sled", "127.0.0.1:4018")] {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = temp_dir.path().join("backups").join("b1");
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&[
+                "--engine",
+                engine,
+                "--addr",
+                addr,
+                "--backup-dir",
+                "backups",
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["backup", "b1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        // an existing directory, or one outside the backup directory
+        let outside = temp_dir.path().join("outside");
+        for dest in &["b1", "../outside", outside.to_str().unwrap()] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["backup", dest, "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .failure();
+        }
+        assert!(!outside.exists());
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr])
+            .current_dir(&backup_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        // without a backup directory, backups are refused
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["backup", "b2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--read-only", "--backup-dir", "backups"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_dump_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let store = KvStore::open(&source).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set(&b"\xff\x00"[..], &b"\r\n\x01"[..]).unwrap();
+    store.set("key2", "value2").unwrap();
+    store.remove("key2").unwrap();
+    drop(store);
+
+    let kvs_dump = temp_dir.path().join("kvs.jsonl");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "--output", kvs_dump.to_str().unwrap()])
+        .arg(&source)
+        .assert()
+        .success()
+        .stderr(contains("dumped 2 pairs"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "restore",
+            "--engine",
+            "sled",
+            "--input",
+            kvs_dump.to_str().unwrap(),
+        ])
+        .arg(temp_dir.path().join("sled"))
+        .assert()
+        .success()
+        .stderr(contains("restored 2 pairs"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", "--input", kvs_dump.to_str().unwrap()])
+        .arg(&source)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    let sled = SledKvsEngine::open(temp_dir.path().join("sled")).unwrap();
+    assert_eq!(sled.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(sled.get("key2").unwrap(), None);
+    drop(sled);
+
+    let sled_dump = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(temp_dir.path().join("sled"))
+        .output()
+        .unwrap();
+    assert!(sled_dump.status.success());
+    assert_eq!(sled_dump.stdout, fs::read(&kvs_dump).unwrap());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("restore")
+        .arg(temp_dir.path().join("kvs"))
+        .with_stdin()
+        .buffer("{\"key\":\"a2V5\",\"value\":\"not base64!\"}\n")
+        .assert()
+        .failure()
+        .stderr(contains("line 1"));
+}
+
+#[test]
+fn cli_dump_restore_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let store = KvStore::open(&source).unwrap();
+    store.set("key1", "value1").unwrap();
+    store
+        .set_with_ttl("key2", "value2", Duration::from_secs(3600))
+        .unwrap();
+    drop(store);
+
+    let dump = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("dump")
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(dump.status.success());
+    let dump = String::from_utf8(dump.stdout).unwrap();
+    assert!(!dump.lines().next().unwrap().contains("expires_at"));
+    assert!(dump.lines().nth(1).unwrap().contains("expires_at"));
+
+    // later lines of a key win, an expired one removes it
+    let input = format!(
+        "{}{}{}",
+        dump,
+        "{\"key\":\"a2V5Mw==\",\"value\":\"dmFsdWUz\"}\n",
+        "{\"key\":\"a2V5MQ==\",\"value\":\"dmFsdWUx\",\"expires_at\":1}\n",
+    );
+    for engine in &["kvs", "sled"] {
+        let dest = temp_dir.path().join(engine);
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .arg("restore")
+            .arg("--engine")
+            .arg(engine)
+            .arg(&dest)
+            .with_stdin()
+            .buffer(input.clone())
+            .assert()
+            .success()
+            .stderr(contains("restored 4 pairs"));
+        let expires_at = match *engine {
+            "kvs" => {
+                let store = KvStore::open(&dest).unwrap();
+                assert_eq!(store.get("key1").unwrap(), None);
+                assert_eq!(store.get("key3").unwrap(), Some("value3".to_owned()));
+                store.expires_at("key2").unwrap()
+            }
+            _ => {
+                let store = SledKvsEngine::open(&dest).unwrap();
+                assert_eq!(store.get("key1").unwrap(), None);
+                assert_eq!(store.get("key3").unwrap(), Some("value3".to_owned()));
+                store.expires_at("key2").unwrap()
+            }
+        };
+        let left = expires_at
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(left > Duration::from_secs(3500));
+    }
+
+    // a failure partway through leaves nothing behind
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("restore")
+        .arg(temp_dir.path().join("broken"))
+        .with_stdin()
+        .buffer(format!("{}not json\n", dump))
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("broken").exists());
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn cli_verify_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    fs::write(
+        source.join("kvs.store"),
+        "{\"Set\":[\"a\",\"1\"]}\nnot json\n{\"Set\":[\"b\",\"2\"]}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(&source)
+        .assert()
+        .failure()
+        .stdout(contains("kvs.store: 9 bytes at offset 18: malformed"));
+
+    let dest = temp_dir.path().join("repaired");
+    let report = temp_dir.path().join("report.txt");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair", "--report", report.to_str().unwrap()])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(contains("salvaged 2 records, lost 9 bytes"));
+    let report = fs::read_to_string(report).unwrap();
+    assert!(report.contains("9 bytes at offset 18: malformed"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(&dest)
+        .assert()
+        .success();
+    let store = KvStore::open(&dest).unwrap();
+    assert_eq!(store.get("b").unwrap(), Some("2".to_owned()));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +791,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the next server can't take the directory until this one is gone
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +874,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
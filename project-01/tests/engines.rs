@@ -0,0 +1,489 @@
+// Tests every engine has to pass, run against each of them
+use kvs::{KvStore, KvsEngine, KvsSnapshot, Result, SledKvsEngine, WriteBatch};
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+trait TestEngine: KvsEngine + Sized {
+    fn open(path: &Path) -> Result<Self>;
+    /// Rewrite the stored data, for engines that compact on demand
+    fn compact(&mut self) -> Result<()>;
+}
+
+impl TestEngine for KvStore {
+    fn open(path: &Path) -> Result<Self> {
+        KvStore::open(path)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.slink()
+    }
+}
+
+impl TestEngine for SledKvsEngine {
+    fn open(path: &Path) -> Result<Self> {
+        SledKvsEngine::open(path)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! engine_tests {
+    ($($name:ident),* $(,)?) => {
+        mod kvs_engine {
+            use kvs::{KvStore, Result};
+            $(
+                #[test]
+                fn $name() -> Result<()> {
+                    super::$name::<KvStore>()
+                }
+            )*
+        }
+
+        mod sled_engine {
+            use kvs::{Result, SledKvsEngine};
+            $(
+                #[test]
+                fn $name() -> Result<()> {
+                    super::$name::<SledKvsEngine>()
+                }
+            )*
+        }
+    };
+}
+
+engine_tests!(
+    apply_batch,
+    conditional_writes,
+    concurrent_compare_and_swap,
+    concurrent_remove,
+    expiring_keys,
+    scan_keys,
+    binary_keys_and_values,
+    consistent_snapshots,
+    snapshot_isolation,
+    checkpoint_during_batches,
+);
+
+// Should apply every put and delete of a batch
+fn apply_batch<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "new1".to_owned())
+        .remove("key2".to_owned())
+        .remove("key4".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.apply_batch(batch)?;
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let mut store = E::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Conditional writes should only go ahead when their condition holds
+fn conditional_writes<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+
+    assert!(store
+        .set_if_present("key1".to_owned(), "value1".to_owned())
+        .unwrap_err()
+        .is_condition_failed());
+    store.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+    assert!(store
+        .set_if_absent("key1".to_owned(), "other".to_owned())
+        .unwrap_err()
+        .is_condition_failed());
+    store.set_if_present("key1".to_owned(), "value2".to_owned())?;
+    assert!(store
+        .compare_and_swap("key1".to_owned(), "value1".to_owned(), "other".to_owned())
+        .unwrap_err()
+        .is_condition_failed());
+    store.compare_and_swap("key1".to_owned(), "value2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert!(store
+        .compare_and_swap("key1".to_owned(), "value3".to_owned(), "other".to_owned())
+        .unwrap_err()
+        .is_condition_failed());
+    store.set_if_absent("key1".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent compare-and-swap increments should never lose an update
+fn concurrent_compare_and_swap<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                loop {
+                    let current = store.get("counter".to_owned()).unwrap().unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    match store.compare_and_swap("counter".to_owned(), current, next) {
+                        Ok(()) => break,
+                        Err(e) => assert!(e.is_condition_failed()),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Of threads removing the same keys at once, only one should remove each
+fn concurrent_remove<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let barrier = Arc::new(Barrier::new(8));
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let (store, barrier) = (store.clone(), barrier.clone());
+        handles.push(thread::spawn(move || {
+            let mut removed = 0;
+            for i in 0..200 {
+                barrier.wait();
+                match store.remove(format!("key{}", i)) {
+                    Ok(()) => removed += 1,
+                    Err(e) => assert!(e.is_key_not_found()),
+                }
+            }
+            removed
+        }));
+    }
+    let removed: u32 = handles.into_iter().map(|x| x.join().unwrap()).sum();
+
+    assert_eq!(removed, 200);
+    assert_eq!(store.stats()?.keys, 0);
+
+    Ok(())
+}
+
+// Expiring keys should disappear once their time is up, also after reopening
+fn expiring_keys<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(200);
+
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), ttl * 100)?;
+    store.set_with_ttl("persisted".to_owned(), "value3".to_owned(), ttl)?;
+    store.expire("persisted".to_owned(), None)?;
+    store.set("extended".to_owned(), "value4".to_owned())?;
+    store.expire("extended".to_owned(), Some(ttl * 100))?;
+    assert!(store
+        .expire("missing".to_owned(), Some(ttl))
+        .unwrap_err()
+        .is_key_not_found());
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+    let expires_at = store.expires_at("long".to_owned())?.unwrap();
+    assert!(expires_at > SystemTime::now() + ttl * 99);
+    assert!(expires_at <= SystemTime::now() + ttl * 100);
+    assert_eq!(store.expires_at("persisted".to_owned())?, None);
+    assert!(store
+        .expires_at("missing".to_owned())
+        .unwrap_err()
+        .is_key_not_found());
+
+    thread::sleep(ttl * 2);
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(store
+        .expires_at("short".to_owned())
+        .unwrap_err()
+        .is_key_not_found());
+    assert!(store
+        .remove("short".to_owned())
+        .unwrap_err()
+        .is_key_not_found());
+    assert!(store
+        .expire("short".to_owned(), None)
+        .unwrap_err()
+        .is_key_not_found());
+    store.set_if_absent("short".to_owned(), "value5".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "value6".to_owned(), ttl)?;
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get("short".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(
+            store.get("persisted".to_owned())?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(store.get("extended".to_owned())?, Some("value4".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    thread::sleep(ttl * 2);
+
+    let mut store = E::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    store.compact()?;
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should scan ranges and prefixes of keys in key order
+fn scan_keys<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = E::open(temp_dir.path())?;
+    for i in (0..20).rev() {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("key05".to_owned())?;
+    store.set_with_ttl(
+        "key06".to_owned(),
+        "value6".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    let check = |store: &E| -> Result<()> {
+        let pairs = store.scan("key03".to_owned().."key08".to_owned(), 10)?;
+        assert_eq!(
+            pairs,
+            vec![
+                ("key03".to_owned(), "value3".to_owned()),
+                ("key04".to_owned(), "value4".to_owned()),
+                ("key07".to_owned(), "value7".to_owned()),
+            ]
+        );
+        assert_eq!(keys(store.scan(.., 2)?), vec!["key00", "key01"]);
+        assert_eq!(
+            keys(store.scan("key19".to_owned().., 5)?),
+            vec!["key19", "other"]
+        );
+        assert_eq!(
+            keys(store.scan("z".to_owned().."a".to_owned(), 5)?).len(),
+            0
+        );
+        assert_eq!(
+            keys(store.scan_prefix("key1".to_owned(), 3)?),
+            vec!["key10", "key11", "key12"]
+        );
+        assert_eq!(store.scan_prefix("key".to_owned(), 100)?.len(), 18);
+        assert_eq!(keys(store.scan_prefix("o".to_owned(), 100)?), vec!["other"]);
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+fn binary_keys_and_values<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = E::open(temp_dir.path())?;
+    let key = vec![0xff, b' ', 0x00, b'\n'];
+    let value = vec![b'\r', b'\n', 0xfe, 0x00];
+    store.set(key.clone(), value.clone())?;
+    store.set(vec![0xff, 0xff], b"last".to_vec())?;
+    store.set("text".to_owned(), vec![0x80])?;
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+        assert_eq!(store.get_bytes("text")?, Some(vec![0x80]));
+        // not UTF-8
+        assert!(store.get("text").unwrap_err().is_encoding());
+        assert!(store
+            .scan_prefix("text".to_owned(), 10)
+            .unwrap_err()
+            .is_encoding());
+        assert_eq!(
+            store.scan_prefix_bytes(vec![0xff], 10)?,
+            vec![
+                (key.clone(), value.clone()),
+                (vec![0xff, 0xff], b"last".to_vec())
+            ]
+        );
+        assert_eq!(store.scan_prefix_bytes(vec![0xff, 0xff], 10)?.len(), 1);
+        assert_eq!(store.scan_bytes(.., 1)?[0].0, b"text".to_vec());
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    check(&store)?;
+
+    store.remove(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+// Should see all or none of each batch in a snapshot taken while batches
+// are applied
+fn consistent_snapshots<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("a", "0").set("b", "0");
+    store.apply_batch(batch)?;
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 1..200 {
+            let mut batch = WriteBatch::new();
+            batch.set("a", i.to_string()).set("b", i.to_string());
+            writer.apply_batch(batch)?;
+        }
+        Ok(())
+    });
+    for _ in 0..200 {
+        let snapshot = store.snapshot()?;
+        let a = snapshot.get("a")?;
+        assert!(a.is_some());
+        assert_eq!(a, snapshot.get("b")?);
+    }
+    handle.join().unwrap()?;
+
+    Ok(())
+}
+
+// A snapshot should keep reading what was there when it was taken
+fn snapshot_isolation<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set(vec![0xff], vec![0x80])?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1", "new1")?;
+    store.remove("key2")?;
+    store.set("key3", "value3")?;
+
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3")?, None);
+    assert!(snapshot.get(vec![0xff]).unwrap_err().is_encoding());
+    assert_eq!(
+        snapshot.scan_prefix("key".to_owned(), 10)?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(snapshot.scan_bytes(.., 10)?.len(), 3);
+
+    assert_eq!(store.get("key1")?, Some("new1".to_owned()));
+    assert_eq!(store.scan_prefix("key".to_owned(), 10)?.len(), 2);
+
+    Ok(())
+}
+
+// A checkpoint taken while batches are applied should hold all or none of
+// each, also when its keys are copied apart
+fn checkpoint_during_batches<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    std::fs::create_dir(&dir)?;
+    let store = E::open(&dir)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..2500 {
+        batch.set(format!("key{:04}", i), "value");
+    }
+    batch.set("first", "0").set("last", "0");
+    store.apply_batch(batch)?;
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 1..200 {
+            let mut batch = WriteBatch::new();
+            batch.set("first", i.to_string()).set("last", i.to_string());
+            writer.apply_batch(batch)?;
+        }
+        Ok(())
+    });
+    for i in 0..5 {
+        let dest = temp_dir.path().join(format!("backup{}", i));
+        store.checkpoint(&dest)?;
+        let backup = E::open(&dest)?;
+        assert_eq!(backup.get("first")?, backup.get("last")?);
+        assert_eq!(backup.scan_prefix("key".to_owned(), 3000)?.len(), 2500);
+    }
+    handle.join().unwrap()?;
+
+    Ok(())
+}
+
+// The sled engine should remove expired keys from both of its trees
+// in the background
+#[test]
+fn sled_sweeps_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set_with_ttl("short", "value1", Duration::from_millis(100))?;
+    store.set_with_ttl("long", "value2", Duration::from_secs(100))?;
+    thread::sleep(Duration::from_millis(2500));
+    drop(store);
+
+    let db = sled::open(temp_dir.path().join("sled.store"))?;
+    let expiry = db.open_tree("expiry")?;
+    assert!(db.get("short")?.is_none());
+    assert!(expiry.get("short")?.is_none());
+    assert!(db.get("long")?.is_some());
+    assert!(expiry.get("long")?.is_some());
+
+    Ok(())
+}
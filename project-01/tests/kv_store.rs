@@ -1,6 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Compression, DamageKind, Durability, EncryptionKey, EngineMarker, KvStore, KvStoreConfig,
+    KvsEngine, KvsSnapshot, Result, SledKvsEngine, WriteBatch,
+};
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,20 +132,488 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should read values by offset after manual compaction
+#[test]
+fn get_after_slink() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.slink()?;
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-9", key_id))
+        );
+    }
+
+    // Writes after compaction must land behind the compacted records
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99-9".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should start by itself while writers keep going
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: 16 * 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let log_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..25 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key, format!("{}", iter)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // 20000 records of 26 bytes each without compaction
+    assert!(log_size() < 256 * 1024);
+    for thread_id in 0..4 {
+        for key_id in 0..25 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some("199".to_owned()));
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..4 {
+        for key_id in 0..25 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some("199".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+// Should ignore a compacted generation that was never renamed into place
+#[test]
+fn crash_while_writing_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::write(temp_dir.path().join("2.tmp"), b"{\"Set\":[\"key1\",\"val")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("2.tmp").exists());
+
+    Ok(())
+}
+
+// Should replay correctly when the old generations survive a compaction
+#[test]
+fn crash_before_removing_old_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    let old_gen = fs::read(temp_dir.path().join("1.log"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.slink()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("1.log"), old_gen)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    for key_id in 2..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should pick up a store written before generation files
+#[test]
+fn open_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.store"),
+        "{\"Set\":[\"key1\",\"value1\"]}\n{\"Set\":[\"key2\",\"value2\"]}\n{\"Rm\":\"key2\"}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!temp_dir.path().join("kvs.store").exists());
+
+    Ok(())
+}
+
+fn segment_files(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .expect("unable to list directory")
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Should roll over to a new segment once the current one is full
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(segment_files(temp_dir.path()).len() > 5);
+    for name in segment_files(temp_dir.path()) {
+        // one record may go past the limit before the segment rolls over
+        assert!(fs::metadata(temp_dir.path().join(name))?.len() < 1100);
+    }
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should leave segments holding only live data alone
+#[test]
+fn compaction_keeps_live_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("old{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let stale_segments = segment_files(temp_dir.path());
+    for key_id in 0..100 {
+        store.set(format!("new{}", key_id), format!("{}", key_id))?;
+    }
+    let live_segments: Vec<String> = segment_files(temp_dir.path())
+        .into_iter()
+        .filter(|name| !stale_segments.contains(name))
+        .collect();
+
+    store.slink()?;
+    let after = segment_files(temp_dir.path());
+    assert!(!after.contains(&stale_segments[0]));
+    assert!(after.contains(&live_segments[0]));
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("old{}", key_id))?, Some("19".to_owned()));
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("new{}", key_id))?,
+            Some(format!("{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should upgrade segments holding JSON lines to the binary format
+#[test]
+fn open_json_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":[\"key1\",\"value \\\"1\\\"\"]}\n{\"Set\":[\"key2\",\"value2\"]}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("value \"1\"".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_ne!(fs::read(temp_dir.path().join("1.log"))?[0], b'{');
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("value \"1\"".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should detect a flipped bit in a record
+#[test]
+fn detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&path)?;
+    let value_pos = bytes
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("value not found in log");
+    bytes[value_pos] ^= 0x01;
+    fs::write(&path, bytes)?;
+
+    assert!(store.get("key1".to_owned()).unwrap_err().is_corruption());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert!(e.is_corruption()),
+        Ok(_) => panic!("corrupted store opened"),
+    }
+
+    Ok(())
+}
+
+// Should drop a torn last record wherever the log was cut off
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(source_dir.path())?;
+    let mut ends = vec![0];
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        ends.push(fs::metadata(source_dir.path().join("1.log"))?.len());
+    }
+    drop(store);
+    let bytes = fs::read(source_dir.path().join("1.log"))?;
+
+    for cut in 0..=bytes.len() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let path = temp_dir.path().join("1.log");
+        fs::write(&path, &bytes[..cut])?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        let complete = ends.iter().filter(|&&end| end <= cut as u64).count() - 1;
+        assert_eq!(fs::metadata(&path)?.len(), ends[complete]);
+        for key_id in 0..3 {
+            let expected = if key_id < complete {
+                Some(format!("value{}", key_id))
+            } else {
+                None
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should drop a last record with a bad checksum, but keep the rest
+#[test]
+fn recover_corrupt_last_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&path)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(&path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A damaged length mid-segment should fail to open, not cut off the records after it
+#[test]
+fn detect_corrupt_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    // the high bit of the first record's value length
+    let path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&path)?;
+    let len = bytes.len() as u64;
+    bytes[14] ^= 0x80;
+    fs::write(&path, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert!(e.is_corruption()),
+        Ok(_) => panic!("corrupted store opened"),
+    }
+    assert_eq!(fs::metadata(&path)?.len(), len);
+
+    Ok(())
+}
+
+// Should drop a cut off line of a store written as JSON lines
+#[test]
+fn recover_torn_json_line() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.store"),
+        "{\"Set\":[\"key1\",\"value1\"]}\n{\"Set\":[\"key2\",\"val",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn files_with_extension(dir: &std::path::Path, ext: &str) -> Vec<std::path::PathBuf> {
+    fs::read_dir(dir)
+        .expect("unable to list directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(std::ffi::OsStr::new(ext)))
+        .collect()
+}
+
+// Should load compacted segments from their hint files without reading values
+#[test]
+fn open_from_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.slink()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let hints = files_with_extension(temp_dir.path(), "hint");
+    assert_eq!(hints.len(), 1);
+    // a damaged value goes unnoticed until it is read
+    let log = hints[0].with_extension("log");
+    let mut bytes = fs::read(&log)?;
+    let value_pos = bytes
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("value not found in log");
+    bytes[value_pos] ^= 0x01;
+    fs::write(&log, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key1".to_owned()).unwrap_err().is_corruption());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should replay a compacted segment whose hint file is damaged
+#[test]
+fn open_with_damaged_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.slink()?;
+    drop(store);
+
+    let hints = files_with_extension(temp_dir.path(), "hint");
+    assert_eq!(hints.len(), 1);
+    let mut bytes = fs::read(&hints[0])?;
+    bytes.truncate(bytes.len() - 1);
+    fs::write(&hints[0], bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,6 +621,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
+    // every clone has to be dropped before the directory can be opened again
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
@@ -209,3 +687,929 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Clones that keep segment files open should keep reading while compaction
+// replaces and deletes those files
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let barrier = Arc::new(Barrier::new(9));
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        let handle = thread::spawn(move || {
+            barrier.wait();
+            for i in 0..2000 {
+                let key_id = (i + thread_id) % 100;
+                let value = store.get(format!("key{}", key_id)).unwrap().unwrap();
+                assert!(value.parse::<u32>().unwrap() < 20);
+            }
+        });
+        handles.push(handle);
+    }
+    barrier.wait();
+    for iter in 1..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.slink()?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should keep working in every durability mode
+#[test]
+fn durability_modes() -> Result<()> {
+    for &durability in &[
+        Durability::Always,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::Buffered,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = KvStoreConfig {
+            segment_size: 1024,
+            durability,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+
+        drop(store);
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Concurrent writers that sync every write should all be committed
+#[test]
+fn concurrent_set_with_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 4096,
+        durability: Durability::Always,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store
+                    .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+            store.remove(format!("key{}-0", thread_id)).unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for thread_id in 0..16 {
+        assert_eq!(store.get(format!("key{}-0", thread_id))?, None);
+        for i in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A batch cut off by a crash should leave none of its writes behind
+#[test]
+fn torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "new1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&path)?;
+    bytes.truncate(bytes.len() - 1);
+    fs::write(&path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Expired keys should be swept from the index and compacted away without
+// bringing back the values they replaced
+#[test]
+fn reclaim_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 4096,
+        sweep_interval: Duration::from_millis(50),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let ttl = Duration::from_millis(200);
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("old{}", i))?;
+    }
+    for i in 0..500 {
+        store.set_with_ttl(format!("key{}", i), format!("value{}", i), ttl)?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    thread::sleep(ttl * 2);
+
+    store.slink()?;
+    let size: u64 = files_with_extension(temp_dir.path(), "log")
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(size < 4096, "log is still {} bytes", size);
+
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should keep reading a snapshot after its keys change and compaction
+// deletes the segments it points into
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        segment_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), format!("old{}", i))?;
+    }
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    let snapshot = store.snapshot()?;
+    let old_segments = files_with_extension(temp_dir.path(), "log");
+
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), format!("new{}", i))?;
+    }
+    store.remove("key00".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    store.slink()?;
+    assert!(old_segments.iter().any(|path| !path.exists()));
+
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{:02}", i))?,
+            Some(format!("old{}", i))
+        );
+    }
+    assert_eq!(snapshot.get("other".to_owned())?, None);
+    assert_eq!(
+        snapshot.get("expiring".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(snapshot.scan_prefix("key".to_owned(), 1000)?.len(), 100);
+    assert_eq!(
+        snapshot.scan("key98".to_owned().., 10)?,
+        vec![
+            ("key98".to_owned(), "old98".to_owned()),
+            ("key99".to_owned(), "old99".to_owned()),
+        ]
+    );
+
+    assert_eq!(store.get("key00".to_owned())?, None);
+    assert_eq!(store.get("key01".to_owned())?, Some("new1".to_owned()));
+    assert_eq!(store.get("expiring".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should compress large values and read compressed and plain records mixed
+// in one log, before and after compaction
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compression: Compression::Lz4,
+        compression_threshold: 100,
+        ..KvStoreConfig::default()
+    };
+    let large = |i: usize| format!("{{\"id\":{},\"tags\":[{}]}}", i, "\"tag\",".repeat(200));
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), large(i))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("expiring".to_owned(), large(0), Duration::from_secs(60))?;
+    let raw: usize = (0..101).map(large).map(|x| x.len()).sum();
+    let size: u64 = files_with_extension(temp_dir.path(), "log")
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!((size as usize) < raw / 4, "log is {} bytes", size);
+    drop(store);
+
+    // written uncompressed from here on
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 100..120 {
+        store.set(format!("key{}", i), large(i))?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key0", large(1000)).remove("key1");
+    store.apply_batch(batch)?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some(large(1000)));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        for i in 2..120 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(large(i)));
+        }
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("expiring".to_owned())?, Some(large(0)));
+        Ok(())
+    };
+    check(&store)?;
+    store.slink()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+fn any_file_contains(dir: &std::path::Path, needle: &[u8]) -> bool {
+    fs::read_dir(dir)
+        .expect("unable to list directory")
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .any(|data| data.windows(needle.len()).any(|x| x == needle))
+}
+
+// Should keep keys and values out of the files and notice tampering
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        encryption_key: Some(EncryptionKey::new([7; 32])),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("token1".to_owned(), "secret1".to_owned())?;
+    store.set("token2".to_owned(), "secret2".to_owned())?;
+    store.slink()?;
+    let mut batch = WriteBatch::new();
+    batch.set("token3", "secret3").remove("token2");
+    store.apply_batch(batch)?;
+    store.set_with_ttl(
+        "token4".to_owned(),
+        "secret4".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    drop(store);
+    assert!(!any_file_contains(temp_dir.path(), b"token"));
+    assert!(!any_file_contains(temp_dir.path(), b"secret"));
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("token1".to_owned())?, Some("secret1".to_owned()));
+    assert_eq!(store.get("token2".to_owned())?, None);
+    assert_eq!(store.get("token3".to_owned())?, Some("secret3".to_owned()));
+    assert_eq!(store.get("token4".to_owned())?, Some("secret4".to_owned()));
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert!(e.is_missing_key()),
+        Ok(_) => panic!("opened without the key"),
+    }
+    let other = KvStoreConfig {
+        encryption_key: Some(EncryptionKey::new([8; 32])),
+        ..KvStoreConfig::default()
+    };
+    match KvStore::open_with_config(temp_dir.path(), other) {
+        Err(e) => assert!(e.is_missing_key()),
+        Ok(_) => panic!("opened with the wrong key"),
+    }
+
+    // flip a bit of the first record's ciphertext and fix up its checksum
+    let path = files_with_extension(temp_dir.path(), "log")
+        .into_iter()
+        .min()
+        .unwrap();
+    let mut data = fs::read(&path)?;
+    let field =
+        |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    let len = 15 + field(7) as usize + field(11) as usize;
+    data[len - 1] ^= 1;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&data[..3]);
+    hasher.update(&data[7..len]);
+    data[3..7].copy_from_slice(&hasher.finalize().to_le_bytes());
+    fs::write(&path, data)?;
+    for hint in files_with_extension(temp_dir.path(), "hint") {
+        fs::remove_file(hint)?;
+    }
+    match KvStore::open_with_config(temp_dir.path(), config) {
+        Err(e) => assert!(e.is_tampered()),
+        Ok(_) => panic!("tampered store opened"),
+    }
+
+    Ok(())
+}
+
+// Should reject records and hints written in the clear into an encrypted store
+#[test]
+fn reject_plaintext_in_encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        encryption_key: Some(EncryptionKey::new([7; 32])),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a compacted plain store copied in as an older segment, hint included
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut plain = KvStore::open(plain_dir.path())?;
+    plain.set("key1".to_owned(), "forged".to_owned())?;
+    plain.set("key2".to_owned(), "forged".to_owned())?;
+    plain.slink()?;
+    drop(plain);
+    let hint = files_with_extension(plain_dir.path(), "hint")[0].clone();
+    fs::copy(&hint, temp_dir.path().join("0.hint"))?;
+    fs::copy(hint.with_extension("log"), temp_dir.path().join("0.log"))?;
+    match KvStore::open_with_config(temp_dir.path(), config) {
+        Err(e) => assert!(e.is_tampered()),
+        Ok(_) => panic!("opened a store with records in the clear"),
+    }
+
+    Ok(())
+}
+
+// Should notice a hint file that points a key at the record of another key
+#[test]
+fn detect_misdirected_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.slink()?;
+    drop(store);
+
+    // rename key2 to key3 in the hint and fix up its checksum
+    let path = files_with_extension(temp_dir.path(), "hint")[0].clone();
+    let mut data = fs::read(&path)?;
+    let at = data
+        .windows(4)
+        .position(|w| w == b"key2")
+        .expect("key not found in hint");
+    data[at + 3] = b'3';
+    let body = data.len() - 4;
+    let crc = crc32fast::hash(&data[..body]);
+    data[body..].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&path, data)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.get("key3".to_owned()).unwrap_err().is_corruption());
+    assert!(store
+        .scan(.."key4".to_owned(), 10)
+        .unwrap_err()
+        .is_corruption());
+
+    Ok(())
+}
+
+// Should read records under previous keys and rewrite them under the current one
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_a = EncryptionKey::new([1; 32]);
+    let key_b = EncryptionKey::new([2; 32]);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+
+    let config_a = KvStoreConfig {
+        encryption_key: Some(key_a.clone()),
+        ..KvStoreConfig::default()
+    };
+    match KvStore::open_with_config(temp_dir.path(), config_a.clone()) {
+        Err(e) => assert!(e.is_tampered()),
+        Ok(_) => panic!("read records in the clear under a key"),
+    }
+    let migrating = KvStoreConfig {
+        accept_plaintext: true,
+        ..config_a.clone()
+    };
+    KvStore::open_with_config(temp_dir.path(), migrating)?.rekey()?;
+    let mut store = KvStore::open_with_config(temp_dir.path(), config_a.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.slink()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").set("key3", "value3");
+    store.apply_batch(batch)?;
+    drop(store);
+
+    let config_b = KvStoreConfig {
+        encryption_key: Some(key_b),
+        ..KvStoreConfig::default()
+    };
+    let rotating = KvStoreConfig {
+        previous_keys: vec![key_a],
+        ..config_b.clone()
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+        for i in 1..4 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    let mut store = KvStore::open_with_config(temp_dir.path(), rotating)?;
+    check(&store)?;
+    store.rekey()?;
+    check(&store)?;
+    drop(store);
+    assert!(!any_file_contains(temp_dir.path(), b"value0"));
+
+    let store = KvStore::open_with_config(temp_dir.path(), config_b)?;
+    check(&store)?;
+    drop(store);
+    match KvStore::open_with_config(temp_dir.path(), config_a) {
+        Err(e) => assert!(e.is_missing_key()),
+        Ok(_) => panic!("opened with the retired key"),
+    }
+
+    Ok(())
+}
+
+// Should keep other stores out of the directory while any clone is alive
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert!(e.is_locked()),
+        Ok(_) => panic!("opened a locked directory"),
+    }
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    thread::spawn(move || clone.get("key1".to_owned()))
+        .join()
+        .unwrap()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A sled engine should open again as soon as the last clone is dropped,
+// and stay locked until then
+#[test]
+fn reopen_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..20 {
+        let engine = SledKvsEngine::open(temp_dir.path())?;
+        let clone = engine.clone();
+        drop(engine);
+        match SledKvsEngine::open(temp_dir.path()) {
+            Err(e) => assert!(e.is_locked()),
+            Ok(_) => panic!("opened a locked directory"),
+        }
+        clone.set(format!("key{}", i), "value")?;
+    }
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.stats()?.keys, 20);
+
+    Ok(())
+}
+
+// Should recognize a store written before engine markers and refuse other engines
+#[test]
+fn engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(EngineMarker::read(temp_dir.path())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let marker = EngineMarker::read(temp_dir.path())?.expect("store not recognized");
+    assert_eq!(marker.engine, "kvs");
+    match EngineMarker::claim(temp_dir.path(), "sled") {
+        Err(e) => assert!(e.is_wrong_engine()),
+        Ok(_) => panic!("claimed by the wrong engine"),
+    }
+    assert_eq!(EngineMarker::claim(temp_dir.path(), "kvs")?, marker);
+    assert!(temp_dir.path().join("kvs.engine").is_file());
+
+    // an older format is raised, so older versions refuse the directory
+    fs::write(
+        temp_dir.path().join("kvs.engine"),
+        "{\"engine\":\"kvs\",\"format_version\":1}\n",
+    )?;
+    assert_eq!(
+        EngineMarker::read(temp_dir.path())?.unwrap().format_version,
+        1
+    );
+    assert_eq!(
+        EngineMarker::check(temp_dir.path(), "kvs")?
+            .unwrap()
+            .format_version,
+        1
+    );
+    assert_eq!(EngineMarker::claim(temp_dir.path(), "kvs")?, marker);
+    assert_eq!(EngineMarker::read(temp_dir.path())?.as_ref(), Some(&marker));
+
+    fs::write(
+        temp_dir.path().join("kvs.engine"),
+        format!(
+            "{{\"engine\":\"kvs\",\"format_version\":{}}}\n",
+            marker.format_version + 1
+        ),
+    )?;
+    assert!(EngineMarker::check(temp_dir.path(), "kvs").is_err());
+    assert!(EngineMarker::claim(temp_dir.path(), "kvs").is_err());
+
+    Ok(())
+}
+
+fn directory_contents(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut contents: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.is_file())
+        .map(|path| (path.clone(), fs::read(path).unwrap()))
+        .collect();
+    contents.sort();
+    contents
+}
+
+// Should read a store, even one in use, without touching its files
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(reader
+        .set("key3".to_owned(), "value3".to_owned())
+        .unwrap_err()
+        .is_read_only());
+    assert!(reader.remove("key1".to_owned()).unwrap_err().is_read_only());
+    assert!(reader.remove("key9".to_owned()).unwrap_err().is_read_only());
+    assert!(reader.slink().unwrap_err().is_read_only());
+    drop(reader);
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("kvs.lock"))?;
+    let before = directory_contents(temp_dir.path());
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(reader);
+    assert_eq!(directory_contents(temp_dir.path()), before);
+
+    Ok(())
+}
+
+// Should read a sled engine's files through a copy, once no engine uses them
+#[test]
+fn read_only_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    match SledKvsEngine::open_read_only(temp_dir.path()) {
+        Err(e) => assert!(e.is_locked()),
+        Ok(_) => panic!("copied files in use"),
+    }
+    drop(engine);
+
+    let before = directory_contents(temp_dir.path());
+    let reader = SledKvsEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(reader
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap_err()
+        .is_read_only());
+    let other = SledKvsEngine::open_read_only(temp_dir.path())?;
+    assert_eq!(directory_contents(temp_dir.path()), before);
+    // readers don't keep a writer out once open
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    drop((reader, other));
+
+    Ok(())
+}
+
+// Should count live keys and stale bytes, and report the last compaction
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.log_bytes), (0, 0));
+    assert_eq!(stats.last_compaction, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.stale_bytes.unwrap() > 0);
+    assert!(stats.stale_bytes.unwrap() < stats.log_bytes);
+    assert_eq!(stats.segments, Some(1));
+
+    store.slink()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 1);
+    assert_eq!(compacted.stale_bytes, Some(0));
+    assert!(compacted.log_bytes < stats.log_bytes);
+    assert!(compacted.last_compaction.is_some());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.keys, 1);
+    assert_eq!(reopened.log_bytes, compacted.log_bytes);
+    assert!(reopened.last_compaction.is_some());
+
+    Ok(())
+}
+
+// Should write a checkpoint that opens as a store while writes carry on
+#[test]
+fn checkpoint_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("checkpoint");
+    let config = KvStoreConfig {
+        segment_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let store_dir = temp_dir.path().join("store");
+    fs::create_dir(&store_dir)?;
+    let mut store = KvStore::open_with_config(&store_dir, config)?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    store.slink()?;
+    store.set("key00".to_owned(), "new".to_owned())?;
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 0..100 {
+            writer.set(format!("other{:02}", i), "value".to_owned())?;
+        }
+        Ok(())
+    });
+    store.checkpoint(&dest)?;
+    handle.join().unwrap()?;
+    assert!(store.checkpoint(&dest).is_err());
+
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("key00".to_owned())?, Some("new".to_owned()));
+    for i in 1..100 {
+        assert_eq!(
+            copy.get(format!("key{:02}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(copy.get("expiring".to_owned())?, Some("value".to_owned()));
+    copy.set("key01".to_owned(), "changed".to_owned())?;
+    assert_eq!(store.get("key01".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        EngineMarker::read(&dest)?.map(|marker| marker.engine),
+        Some("kvs".to_owned())
+    );
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(copy.get("expiring".to_owned())?, None);
+
+    let read_only = KvStoreConfig {
+        read_only: true,
+        ..KvStoreConfig::default()
+    };
+    let reader = KvStore::open_with_config(&store_dir, read_only)?;
+    match reader.checkpoint(temp_dir.path().join("from-reader")) {
+        Err(e) => assert!(e.is_read_only()),
+        Ok(_) => panic!("checkpointed a read-only store"),
+    }
+
+    Ok(())
+}
+
+// Should copy a sled engine a page at a time, expiry times included
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path().join("store"))?;
+    for i in 0..2500 {
+        engine.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    engine.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    let dest = temp_dir.path().join("checkpoint");
+    engine.checkpoint(&dest)?;
+    assert!(engine.checkpoint(&dest).is_err());
+    drop(engine);
+
+    let copy = SledKvsEngine::open(&dest)?;
+    assert_eq!(copy.stats()?.keys, 2501);
+    assert_eq!(
+        copy.get("key2499".to_owned())?,
+        Some("value2499".to_owned())
+    );
+    assert_eq!(copy.get("expiring".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(copy.get("expiring".to_owned())?, None);
+    drop(copy);
+
+    let reader = SledKvsEngine::open_read_only(&dest)?;
+    match reader.checkpoint(temp_dir.path().join("from-reader")) {
+        Err(e) => assert!(e.is_read_only()),
+        Ok(_) => panic!("checkpointed a read-only engine"),
+    }
+
+    Ok(())
+}
+
+// Should report damaged records by offset and salvage the rest into a new store
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source)?;
+    let store = KvStore::open(&source)?;
+    // every record takes 25 bytes
+    store.set("key3".to_owned(), "first!".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let dest = temp_dir.path().join("repaired");
+    match KvStore::verify(&source, KvStoreConfig::default()) {
+        Err(e) => assert!(e.is_locked()),
+        Ok(_) => panic!("verified a store in use"),
+    }
+    match KvStore::repair(&source, &dest, KvStoreConfig::default()) {
+        Err(e) => assert!(e.is_locked()),
+        Ok(_) => panic!("repaired a store in use"),
+    }
+    assert!(!dest.exists());
+    drop(store);
+    assert!(KvStore::verify(&source, KvStoreConfig::default())?.is_clean());
+
+    // damage the second write of key3, and leave a torn append behind
+    let log = source.join("1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes[100 + 20] ^= 0xff;
+    bytes.extend_from_slice(&[1, 1, 0, 0, 0]);
+    fs::write(&log, bytes)?;
+    match KvStore::open(&source) {
+        Err(e) => assert!(e.is_corruption()),
+        Ok(_) => panic!("opened a damaged store"),
+    }
+
+    let report = KvStore::verify(&source, KvStoreConfig::default())?;
+    assert_eq!(report.records, 10);
+    assert_eq!(
+        report
+            .damage
+            .iter()
+            .map(|x| (x.offset, x.len, x.kind))
+            .collect::<Vec<_>>(),
+        vec![
+            (100, 25, DamageKind::ChecksumMismatch),
+            (275, 5, DamageKind::Truncated),
+        ]
+    );
+    assert_eq!(report.damaged_bytes(), 30);
+
+    assert_eq!(
+        KvStore::repair(&source, &dest, KvStoreConfig::default())?,
+        report
+    );
+    assert!(KvStore::verify(&dest, KvStoreConfig::default())?.is_clean());
+    let store = KvStore::open(&dest)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("first!".to_owned()));
+    for i in (0..10).filter(|&i| i != 3) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.stats()?.stale_bytes, Some(0));
+
+    Ok(())
+}
+
+// Repair should drop a torn or damaged batch whole, never part of it
+#[test]
+fn repair_damaged_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let write = |name: &str, after: bool| -> Result<std::path::PathBuf> {
+        let dir = temp_dir.path().join(name);
+        fs::create_dir(&dir)?;
+        let store = KvStore::open(&dir)?;
+        store.set("base", "0")?;
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").set("c", "3");
+        store.apply_batch(batch)?;
+        if after {
+            store.set("after", "4")?;
+        }
+        Ok(dir)
+    };
+    let check = |dir: &std::path::Path, records: u64| -> Result<Option<String>> {
+        let dest = dir.with_extension("repaired");
+        let report = KvStore::repair(dir, &dest, KvStoreConfig::default())?;
+        assert_eq!(report.records, records);
+        assert_eq!(report.damage.len(), 1);
+        let store = KvStore::open(&dest)?;
+        assert_eq!(store.get("base")?, Some("0".to_owned()));
+        for key in &["a", "b", "c"] {
+            assert_eq!(store.get(*key)?, None);
+        }
+        store.get("after")
+    };
+
+    // an append of the batch cut off by a crash
+    let torn = write("torn", false)?;
+    let log = torn.join("1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes.pop();
+    fs::write(&log, bytes)?;
+    assert_eq!(check(&torn, 1)?, None);
+    assert_eq!(KvStore::open(&torn)?.get("a")?, None);
+
+    // every record takes 17 bytes, after the 20 of the base record and the
+    // 15 of the batch header; damage the value of b
+    let damaged = write("damaged", true)?;
+    let log = damaged.join("1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes[20 + 15 + 17 + 16] ^= 0xff;
+    fs::write(&log, bytes)?;
+    assert_eq!(check(&damaged, 2)?, Some("4".to_owned()));
+
+    Ok(())
+}